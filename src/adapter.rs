@@ -0,0 +1,507 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use data::{encode_event, Data};
+use namespace::DEFAULT_NAMESPACE;
+use socket::{to_json, Socket};
+use serde::Serialize;
+use serde_json::Value;
+use serde_json::error::Error as JSONError;
+
+/// Keeps track of room membership and delivers broadcasts to rooms.
+/// The default `MemoryAdapter` only knows about the clients of the
+/// current process; an adapter backed by a message broker can be
+/// used to fan broadcasts out across several server instances.
+///
+/// Rooms are scoped to namespaces: a room "chat" in `/a` is distinct
+/// from a room "chat" in `/b`.
+pub trait Adapter: Send + Sync {
+    /// Adds `so` to `room` in the namespace it's connected to.
+    fn add(&self, so: &Socket, room: &str);
+
+    /// Removes `so` from `room` in the namespace it's connected to.
+    fn remove(&self, so: &Socket, room: &str);
+
+    /// Moves `so` from `from` to `to` in the namespace it's connected
+    /// to. Adapters should override this to move it in one step, so
+    /// broadcasts never find it in both rooms or in neither. The
+    /// default removes it, then adds it.
+    fn move_room(&self, so: &Socket, from: &str, to: &str) {
+        self.remove(so, from);
+        self.add(so, to);
+    }
+
+    /// Returns the names of all rooms with at least one client in
+    /// them, in any namespace.
+    fn rooms(&self) -> Vec<String>;
+
+    /// Emits an event to every client in any of `opts.rooms` of
+    /// `opts.namespace`, except the clients in any of `opts.except`.
+    /// Clients in several rooms only get the event once. Returns the
+    /// number of clients the event was sent to.
+    fn broadcast(&self, opts: &BroadcastOptions, event: Value, params: Option<Vec<Data>>) -> usize;
+
+    /// Returns the clients of the current process a broadcast with
+    /// `opts` would be sent to, each of them once.
+    fn clients(&self, opts: &BroadcastOptions) -> Vec<Socket>;
+}
+
+/// Who a broadcast is sent to, and how.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct BroadcastOptions {
+    /// The namespace `rooms` and `except` are in, the default
+    /// namespace if `None`.
+    pub namespace: Option<String>,
+    pub rooms: Vec<String>,
+    /// Rooms whose clients don't get the broadcast. Since every
+    /// client is in a room named after its id, single clients can be
+    /// excluded by their id.
+    pub except: Vec<String>,
+    /// Whether the broadcast may be compressed. engine.io has no
+    /// per-message compression, so only adapters forwarding
+    /// broadcasts elsewhere can make use of this.
+    pub compress: bool,
+    /// Whether the broadcast may be dropped for clients that aren't
+    /// ready to receive it. engine.io can't tell whether a client is
+    /// ready, so the `MemoryAdapter` delivers volatile broadcasts like
+    /// any other.
+    pub volatile: bool,
+}
+
+/// An `Adapter` keeping rooms in memory.
+#[derive(Clone)]
+pub struct MemoryAdapter {
+    // keyed by namespace and room name.
+    rooms: Arc<RwLock<HashMap<(String, String), Vec<Socket>>>>,
+}
+
+unsafe impl Send for MemoryAdapter {}
+unsafe impl Sync for MemoryAdapter {}
+
+impl MemoryAdapter {
+    pub fn new() -> MemoryAdapter {
+        MemoryAdapter { rooms: Arc::new(RwLock::new(HashMap::new())) }
+    }
+}
+
+// What the rooms of a `MemoryAdapter` need to know about their
+// clients.
+#[doc(hidden)]
+pub trait Member: Clone {
+    fn id(&self) -> String;
+    fn namespace(&self) -> Option<String>;
+}
+
+impl Member for Socket {
+    fn id(&self) -> String {
+        Socket::id(self)
+    }
+
+    fn namespace(&self) -> Option<String> {
+        Socket::namespace(self)
+    }
+}
+
+// Both called under the write lock of the rooms, so concurrent joins
+// can't add a client to the same room twice.
+#[doc(hidden)]
+pub fn add_to<M: Member>(map: &mut HashMap<(String, String), Vec<M>>, so: &M, room: &str) {
+    let clients = map.entry(room_key(so.namespace(), room)).or_insert(vec![]);
+    if !clients.iter().any(|s| s.id() == so.id()) {
+        clients.push(so.clone());
+    }
+}
+
+// Rooms emptied by a concurrent leave are skipped.
+fn remove_from<M: Member>(map: &mut HashMap<(String, String), Vec<M>>, so: &M, room: &str) {
+    let key = room_key(so.namespace(), room);
+    let empty = match map.get_mut(&key) {
+        Some(clients) => {
+            clients.retain(|s| s.id() != so.id());
+            clients.is_empty()
+        }
+        None => false,
+    };
+    if empty {
+        map.remove(&key);
+    }
+}
+
+// Called under the write lock of the rooms, so no one sees the client
+// in both rooms or in neither.
+fn move_between<M: Member>(map: &mut HashMap<(String, String), Vec<M>>,
+                           so: &M,
+                           from: &str,
+                           to: &str) {
+    remove_from(map, so, from);
+    add_to(map, so, to);
+}
+
+impl Adapter for MemoryAdapter {
+    fn add(&self, so: &Socket, room: &str) {
+        add_to(&mut self.rooms.write().unwrap(), so, room);
+    }
+
+    fn remove(&self, so: &Socket, room: &str) {
+        remove_from(&mut self.rooms.write().unwrap(), so, room);
+    }
+
+    fn move_room(&self, so: &Socket, from: &str, to: &str) {
+        move_between(&mut self.rooms.write().unwrap(), so, from, to);
+    }
+
+    fn rooms(&self) -> Vec<String> {
+        let map = self.rooms.read().unwrap();
+        let names: HashSet<&String> = map.keys().map(|&(_, ref room)| room).collect();
+        names.into_iter().cloned().collect()
+    }
+
+    fn broadcast(&self, opts: &BroadcastOptions, event: Value, params: Option<Vec<Data>>) -> usize {
+        let (json, attachments) = encode_event(event, params);
+        self.clients(opts)
+            .iter()
+            .filter(|so| so.try_emit_encoded(json.clone(), attachments.clone()).is_ok())
+            .count()
+    }
+
+    fn clients(&self, opts: &BroadcastOptions) -> Vec<Socket> {
+        let map = self.rooms.read().unwrap();
+
+        let mut excluded: HashSet<String> = opts.except.iter().cloned().collect();
+        for room in opts.except.iter() {
+            if let Some(clients) = map.get(&room_key(opts.namespace.clone(), room)) {
+                excluded.extend(clients.iter().map(|so| so.id()));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let mut recipients = vec![];
+        for room in opts.rooms.iter() {
+            if let Some(clients) = map.get(&room_key(opts.namespace.clone(), room)) {
+                for so in clients.iter() {
+                    let id = so.id();
+                    if !excluded.contains(&id) && seen.insert(id) {
+                        recipients.push(so.clone());
+                    }
+                }
+            }
+        }
+        recipients
+    }
+}
+
+#[doc(hidden)]
+pub fn room_key(namespace: Option<String>, room: &str) -> (String, String) {
+    (namespace.unwrap_or(DEFAULT_NAMESPACE.to_string()), room.to_string())
+}
+
+/// A change in room membership, passed to the handler set with
+/// `Server::on_room_event`. Only rooms joined with `Socket::join`
+/// are reported, not the rooms clients are implicitly in.
+#[derive(PartialEq, Clone, Debug)]
+pub enum RoomEvent {
+    Joined { socket_id: String, room: String },
+    /// The client left the room, either with `Socket::leave` or by
+    /// disconnecting.
+    Left { socket_id: String, room: String },
+}
+
+/// Emits events to the clients in a set of rooms of a namespace,
+/// obtained with `Server::to`, `Namespace::to` or `Socket::to`.
+/// Further rooms, exclusions and options can be chained before
+/// emitting, e.g.
+/// `server.to("lobby").to("game").except(id).emit(event, None)`.
+pub struct BroadcastOperator {
+    adapter: Arc<Adapter>,
+    opts: BroadcastOptions,
+}
+
+impl BroadcastOperator {
+    #[doc(hidden)]
+    pub fn new(adapter: Arc<Adapter>) -> BroadcastOperator {
+        BroadcastOperator {
+            adapter: adapter,
+            opts: BroadcastOptions::default(),
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn in_namespace(mut self, namespace: Option<String>) -> BroadcastOperator {
+        self.opts.namespace = namespace;
+        self
+    }
+
+    /// Also emit to the clients in `room`.
+    pub fn to(mut self, room: &str) -> BroadcastOperator {
+        if !self.opts.rooms.iter().any(|r| r == room) {
+            self.opts.rooms.push(room.to_string());
+        }
+        self
+    }
+
+    /// Don't emit to the clients in `room`, or to the client with the
+    /// id `room`.
+    pub fn except(mut self, room: &str) -> BroadcastOperator {
+        if !self.opts.except.iter().any(|r| r == room) {
+            self.opts.except.push(room.to_string());
+        }
+        self
+    }
+
+    /// Set whether events may be compressed, see
+    /// `BroadcastOptions::compress`.
+    pub fn compress(mut self, compress: bool) -> BroadcastOperator {
+        self.opts.compress = compress;
+        self
+    }
+
+    /// Set whether events may be dropped for clients that aren't
+    /// ready, see `BroadcastOptions::volatile`.
+    pub fn volatile(mut self, volatile: bool) -> BroadcastOperator {
+        self.opts.volatile = volatile;
+        self
+    }
+
+    /// Emits an event with the value `event` and parameters `params`,
+    /// returning the number of clients the event was sent to.
+    pub fn emit(&self, event: Value, params: Option<Vec<Data>>) -> usize {
+        self.adapter.broadcast(&self.opts, event, params)
+    }
+
+    /// Emits an event with `arg` serialized to JSON as its only
+    /// argument, returning the number of clients the event was sent
+    /// to. `arg` is serialized once for all of them.
+    pub fn emit_one<T: Serialize>(&self, event: &str, arg: &T) -> Result<usize, JSONError> {
+        let json = try!(to_json(arg));
+        Ok(self.emit(Value::String(event.to_string()), Some(vec![Data::JSON(json)])))
+    }
+
+    /// Like `emit`, but only emits to the clients `predicate` returns
+    /// `true` for, e.g. to reach the admins in a room. Room membership
+    /// is resolved first, with `Adapter::clients`, so only clients of
+    /// the current process are considered.
+    pub fn emit_where<P>(&self, predicate: P, event: Value, params: Option<Vec<Data>>) -> usize
+        where P: Fn(&Socket) -> bool
+    {
+        let (json, attachments) = encode_event(event, params);
+        self.adapter
+            .clients(&self.opts)
+            .iter()
+            .filter(|so| predicate(so))
+            .filter(|so| so.try_emit_encoded(json.clone(), attachments.clone()).is_ok())
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread;
+
+    use super::{add_to, move_between, remove_from, room_key, Adapter, BroadcastOperator,
+                BroadcastOptions, Member};
+    use data::{encode_event, Data};
+    use namespace::Namespace;
+    use packet::Packet;
+    use socket::Socket;
+    use serde::{Serialize, Serializer};
+    use serde_json::{self, Value};
+
+    struct RecordingAdapter {
+        broadcasts: Mutex<Vec<(BroadcastOptions, Value)>>,
+        // the packets the broadcasts would be sent as.
+        packets: Mutex<Vec<String>>,
+    }
+
+    impl RecordingAdapter {
+        fn new() -> RecordingAdapter {
+            RecordingAdapter {
+                broadcasts: Mutex::new(vec![]),
+                packets: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    struct Score {
+        player: &'static str,
+        points: u32,
+    }
+
+    impl Serialize for Score {
+        fn serialize<S: Serializer>(&self, s: &mut S) -> Result<(), S::Error> {
+            let mut state = try!(s.serialize_struct("Score", 2));
+            try!(s.serialize_struct_elt(&mut state, "player", self.player));
+            try!(s.serialize_struct_elt(&mut state, "points", self.points));
+            s.serialize_struct_end(state)
+        }
+    }
+
+    // Stands in for a socket in the rooms of a `MemoryAdapter`.
+    #[derive(Clone, PartialEq, Debug)]
+    struct Client(String);
+
+    impl Member for Client {
+        fn id(&self) -> String {
+            self.0.clone()
+        }
+
+        fn namespace(&self) -> Option<String> {
+            None
+        }
+    }
+
+    impl Adapter for RecordingAdapter {
+        fn add(&self, _: &Socket, _: &str) {}
+
+        fn remove(&self, _: &Socket, _: &str) {}
+
+        fn rooms(&self) -> Vec<String> {
+            vec![]
+        }
+
+        fn broadcast(&self, opts: &BroadcastOptions, event: Value, params: Option<Vec<Data>>)
+                     -> usize {
+            let (json, _) = encode_event(event.clone(), params);
+            self.packets.lock().unwrap().push(Packet::new_event(None, None, 0, json).encode());
+            self.broadcasts.lock().unwrap().push((opts.clone(), event));
+            2
+        }
+
+        fn clients(&self, _: &BroadcastOptions) -> Vec<Socket> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn broadcast_goes_through_adapter() {
+        let adapter = Arc::new(RecordingAdapter::new());
+        let op = BroadcastOperator::new(adapter.clone()).to("lobby").except("a");
+
+        assert_eq!(op.emit(Value::String("news".to_string()), None), 2);
+        let opts = BroadcastOptions {
+            rooms: vec!["lobby".to_string()],
+            except: vec!["a".to_string()],
+            ..BroadcastOptions::default()
+        };
+        assert_eq!(*adapter.broadcasts.lock().unwrap(),
+                   vec![(opts, Value::String("news".to_string()))]);
+    }
+
+    #[test]
+    fn chained_options() {
+        let adapter = Arc::new(RecordingAdapter::new());
+        BroadcastOperator::new(adapter.clone())
+            .to("lobby")
+            .to("game")
+            .to("lobby")
+            .except("a")
+            .except("muted")
+            .volatile(true)
+            .emit(Value::String("news".to_string()), None);
+
+        let broadcasts = adapter.broadcasts.lock().unwrap();
+        let opts = &broadcasts[0].0;
+        assert_eq!(opts.rooms, vec!["lobby".to_string(), "game".to_string()]);
+        assert_eq!(opts.except, vec!["a".to_string(), "muted".to_string()]);
+        assert!(opts.volatile);
+        assert!(!opts.compress);
+    }
+
+    #[test]
+    fn namespaced_rooms() {
+        let adapter = Arc::new(RecordingAdapter::new());
+        BroadcastOperator::new(adapter.clone()).to("chat").emit(Value::Null, None);
+        Namespace::new("/a".to_string(), adapter.clone()).to("chat").emit(Value::Null, None);
+
+        let broadcasts = adapter.broadcasts.lock().unwrap();
+        assert_eq!(broadcasts[0].0.namespace, None);
+        assert_eq!(broadcasts[1].0.namespace, Some("/a".to_string()));
+        assert_eq!(broadcasts[1].0.rooms, vec!["chat".to_string()]);
+    }
+
+    #[test]
+    fn typed_emit() {
+        let adapter = Arc::new(RecordingAdapter::new());
+        let score = Score { player: "ann", points: 12 };
+        let sent = BroadcastOperator::new(adapter.clone()).to("game").emit_one("score", &score);
+        assert_eq!(sent.ok(), Some(2));
+        assert_eq!(adapter.broadcasts.lock().unwrap()[0].0.rooms, vec!["game".to_string()]);
+
+        let packets = adapter.packets.lock().unwrap();
+        let packet = Packet::from_bytes(packets[0].as_bytes()).expect("Decoding packet");
+        let expected: Value = serde_json::from_str("[\"score\",{\"player\":\"ann\",\"points\":12}]")
+            .unwrap();
+        assert_eq!(packet.data, Some(expected));
+    }
+
+    #[test]
+    fn concurrent_close_and_leave() {
+        let rooms = Arc::new(RwLock::new(HashMap::new()));
+        let clients: Vec<Client> = (0..20).map(|i| Client(i.to_string())).collect();
+        for client in clients.iter() {
+            for room in &["a", "b", &client.0[..]] {
+                add_to(&mut rooms.write().unwrap(), client, room);
+            }
+        }
+
+        // each client leaves "a" while closing, which leaves every
+        // room it's in, including "a" once more.
+        let threads: Vec<_> = clients.into_iter()
+            .flat_map(|client| {
+                let (leaving, closing) = (rooms.clone(), rooms.clone());
+                let other = client.clone();
+                vec![thread::spawn(move || remove_from(&mut leaving.write().unwrap(), &other, "a")),
+                     thread::spawn(move || {
+                         for room in &["a", "b", &client.0[..]] {
+                             remove_from(&mut closing.write().unwrap(), &client, room);
+                         }
+                     })]
+            })
+            .collect();
+        for t in threads {
+            t.join().expect("Removing client");
+        }
+        assert!(rooms.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_moves() {
+        let rooms = Arc::new(RwLock::new(HashMap::new()));
+        let client = Client("a".to_string());
+        add_to(&mut rooms.write().unwrap(), &client, "lobby");
+
+        // the client moves back and forth, while a broadcast looks it
+        // up in both rooms.
+        let movers: Vec<_> = (0..4)
+            .map(|i| {
+                let (rooms, client) = (rooms.clone(), client.clone());
+                let (from, to) = if i % 2 == 0 { ("lobby", "game") } else { ("game", "lobby") };
+                thread::spawn(move || for _ in 0..500 {
+                    move_between(&mut rooms.write().unwrap(), &client, from, to);
+                })
+            })
+            .collect();
+        let reader = {
+            let rooms = rooms.clone();
+            thread::spawn(move || for _ in 0..2000 {
+                let map = rooms.read().unwrap();
+                let found = ["lobby", "game"]
+                    .iter()
+                    .filter(|room| {
+                        map.get(&room_key(None, room))
+                            .map_or(false, |clients| clients.iter().any(|c| c.id() == "a"))
+                    })
+                    .count();
+                assert_eq!(found, 1);
+            })
+        };
+        for t in movers {
+            t.join().expect("Moving client");
+        }
+        reader.join().expect("Looking up client");
+
+        let map = rooms.read().unwrap();
+        assert_eq!(map.values().map(|clients| clients.len()).sum::<usize>(), 1);
+    }
+}
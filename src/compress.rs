@@ -0,0 +1,71 @@
+// Compression of binary attachments sent as `Data::CompressedBinary`,
+// in the LZ4 block format. It needs no state shared between the two
+// sides, and decompressing is cheap, so it suits small attachments
+// as well as large ones.
+
+use lz4_flex::block;
+
+// LZ4 can't expand data more than about 255 times, so smaller blocks
+// don't need a buffer as large as the limit.
+const MAX_RATIO: usize = 255;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    block::compress(input)
+}
+
+/// Returns `None` if `input` isn't a valid LZ4 block, or would
+/// decompress to more than `max` bytes.
+pub fn decompress(input: &[u8], max: usize) -> Option<Vec<u8>> {
+    let max = max.min(input.len().saturating_mul(MAX_RATIO));
+    block::decompress(input, max).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress};
+
+    const MAX: usize = 1 << 20;
+
+    #[test]
+    fn round_trip() {
+        let log: Vec<u8> = (0..200)
+            .flat_map(|i| format!("{{\"level\":\"info\",\"line\":{}}}\n", i).into_bytes())
+            .collect();
+        let sparse = vec![0u8; 70000];
+        let noise: Vec<u8> = (0..1000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+
+        for input in &[vec![], vec![7], b"abcabcabcabcabcabc".to_vec(), log, sparse, noise] {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed, MAX).as_ref(), Some(input));
+        }
+        assert!(compress(&[0u8; 70000]).len() < 400);
+    }
+
+    #[test]
+    fn known_block() {
+        // 3 literals, then a 7 byte match at offset 3, then the
+        // remaining literals.
+        let block = [0x33, b'a', b'b', b'c', 3, 0, 0x50, b'a', b'b', b'c', b'a', b'b'];
+        assert_eq!(decompress(&block, MAX), Some(b"abcabcabcaabcab".to_vec()));
+    }
+
+    #[test]
+    fn invalid_blocks() {
+        assert_eq!(decompress(&[], MAX), None);
+        // literals past the end
+        assert_eq!(decompress(&[0x40, 1, 2], MAX), None);
+        // offset before the start of the output
+        assert_eq!(decompress(&[0x10, 1, 5, 0, 0x00], MAX), None);
+        // zero offset
+        assert_eq!(decompress(&[0x10, 1, 0, 0, 0x00], MAX), None);
+    }
+
+    #[test]
+    fn size_limit() {
+        let bomb = compress(&vec![0u8; 1 << 20]);
+        assert!(bomb.len() < 8192);
+        assert_eq!(decompress(&bomb, 1 << 20).map(|b| b.len()), Some(1 << 20));
+        assert_eq!(decompress(&bomb, (1 << 20) - 1), None);
+        assert_eq!(decompress(&bomb, 1024), None);
+    }
+}
@@ -1,25 +1,42 @@
+use std::sync::{Arc, Mutex};
+
 use serde_json::Value;
 use serde_json::de::from_str;
+use serde_json::ser::{to_string, to_string_pretty};
+
+use compress::{compress, decompress};
+use packet::{JsonFormat, Packet};
 
 #[derive(Clone)]
 pub enum Data {
     JSON(Value),
     Binary(Vec<u8>),
+    /// Binary data sent compressed in the LZ4 block format, for large
+    /// compressible payloads such as logs. socket.io doesn't define
+    /// compressed attachments, so the attachment's placeholder is
+    /// marked with `"compression":"lz4"`, and only clients knowing
+    /// this convention can read it; the stock JS client can't.
+    /// Compressed attachments received are passed to handlers as
+    /// received, unless the socket is set to decompress them, see
+    /// `Socket::set_decompress_attachments`.
+    CompressedBinary(Vec<u8>),
 }
 
 #[doc(hidden)]
 pub fn encode_data(data: Vec<Data>) -> (Value, Vec<Vec<u8>>) {
     let mut json = vec![];
     let mut binary = vec![];
-    let mut placeholder_num = 0;
 
     for value in data {
         json.push(match value {
             Data::JSON(v) => v,
             Data::Binary(b) => {
                 binary.push(b);
-                placeholder_num = placeholder_num + 1;
-                placeholder(placeholder_num)
+                placeholder(binary.len() - 1, None)
+            }
+            Data::CompressedBinary(b) => {
+                binary.push(compress(&b));
+                placeholder(binary.len() - 1, Some(COMPRESSION))
             }
         })
     }
@@ -27,6 +44,253 @@ pub fn encode_data(data: Vec<Data>) -> (Value, Vec<Vec<u8>>) {
     (Value::Array(json), binary)
 }
 
-fn placeholder(num: usize) -> Value {
-    from_str(&format!("{{\"_placeholder\":true,\"num\": {}}}", num)).unwrap()
+/// Encodes the event `event` with the parameters `params`, splitting
+/// out binary parameters as attachments.
+#[doc(hidden)]
+pub fn encode_event(event: Value, params: Option<Vec<Data>>) -> (Value, Vec<Vec<u8>>) {
+    let mut data = vec![Data::JSON(event)];
+    if let Some(params) = params {
+        data.extend(params);
+    }
+    encode_data(data)
+}
+
+/// An event encoded once with `encode_event`, to be emitted to many
+/// clients without copying it for each. Its JSON is serialized once
+/// for each format the clients use, and its attachments are only
+/// copied when handed to engine.io, which takes buffers of its own.
+#[doc(hidden)]
+pub struct SharedEvent {
+    json: Value,
+    attachments: Vec<Vec<u8>>,
+    // `json` serialized compactly and pretty printed, on first use.
+    serialized: Mutex<(Option<Arc<String>>, Option<Arc<String>>)>,
+}
+
+impl SharedEvent {
+    pub fn new(event: Value, params: Option<Vec<Data>>) -> SharedEvent {
+        let (json, attachments) = encode_event(event, params);
+        SharedEvent {
+            json: json,
+            attachments: attachments,
+            serialized: Mutex::new((None, None)),
+        }
+    }
+
+    pub fn json(&self) -> &Value {
+        &self.json
+    }
+
+    pub fn attachments(&self) -> &[Vec<u8>] {
+        &self.attachments
+    }
+
+    /// Returns the event's JSON serialized in `format`.
+    pub fn serialized(&self, format: JsonFormat) -> Arc<String> {
+        let mut serialized = self.serialized.lock().unwrap();
+        let cached = match format {
+            JsonFormat::Compact => &mut serialized.0,
+            JsonFormat::Pretty => &mut serialized.1,
+        };
+        if cached.is_none() {
+            let json = match format {
+                JsonFormat::Compact => to_string(&self.json),
+                JsonFormat::Pretty => to_string_pretty(&self.json),
+            };
+            *cached = Some(Arc::new(json.unwrap()));
+        }
+        cached.clone().unwrap()
+    }
+}
+
+const COMPRESSION: &'static str = "lz4";
+
+fn placeholder(num: usize, compression: Option<&str>) -> Value {
+    let json = match compression {
+        Some(c) => format!("{{\"_placeholder\":true,\"num\":{},\"compression\":\"{}\"}}", num, c),
+        None => format!("{{\"_placeholder\":true,\"num\": {}}}", num),
+    };
+    from_str(&json).unwrap()
+}
+
+/// Returns the attachments of `packet`. If `max_decompressed` is set,
+/// the ones sent as `Data::CompressedBinary` are decompressed, as long
+/// as they don't grow past `max_decompressed` bytes. Attachments that
+/// fail to decompress are returned as received.
+#[doc(hidden)]
+pub fn decode_attachments(packet: &Packet,
+                          max_decompressed: Option<usize>)
+                          -> Option<Vec<Vec<u8>>> {
+    let mut attachments = match packet.get_attachments() {
+        Some(attachments) => attachments,
+        None => return None,
+    };
+    let max = match max_decompressed {
+        Some(max) => max,
+        None => return Some(attachments),
+    };
+    let mut compressed = vec![];
+    if let Some(ref data) = packet.data {
+        compressed_attachments(data, &mut compressed);
+    }
+    for num in compressed {
+        if let Some(bytes) = attachments.get_mut(num) {
+            if let Some(decompressed) = decompress(bytes, max) {
+                *bytes = decompressed;
+            }
+        }
+    }
+    Some(attachments)
+}
+
+fn compressed_attachments(value: &Value, nums: &mut Vec<usize>) {
+    match *value {
+        Value::Array(ref values) => {
+            for v in values {
+                compressed_attachments(v, nums);
+            }
+        }
+        Value::Object(ref map) if is_placeholder(value) => {
+            if map.get("compression").and_then(|c| c.as_str()) == Some(COMPRESSION) {
+                if let Some(num) = map.get("num").and_then(|n| n.as_u64()) {
+                    nums.push(num as usize);
+                }
+            }
+        }
+        Value::Object(ref map) => {
+            for v in map.values() {
+                compressed_attachments(v, nums);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns `true` if `value` stands in for an attachment.
+#[doc(hidden)]
+pub fn is_placeholder(value: &Value) -> bool {
+    value.find("_placeholder").and_then(|p| p.as_bool()) == Some(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{decode_attachments, encode_data, encode_event, is_placeholder, Data, SharedEvent};
+    use packet::{JsonFormat, Opcode, Packet};
+    use serde_json::Value;
+
+    #[test]
+    fn binary_event() {
+        let (json, binary) = encode_data(vec![Data::JSON(Value::String("chunk".to_string())),
+                                              Data::Binary(vec![1, 2, 3])]);
+        let packet = Packet::new_event(None, None, binary.len(), json);
+
+        assert_eq!(packet.encode(),
+                   "51-[\"chunk\",{\"_placeholder\":true,\"num\":0}]");
+        assert_eq!(binary, vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn shared_event() {
+        let event = SharedEvent::new(Value::String("chunk".to_string()),
+                                     Some(vec![Data::Binary(vec![1, 2, 3])]));
+        assert_eq!(event.attachments(), &[vec![1, 2, 3]][..]);
+
+        let packet = Packet::new_event(None, Some(4), 1, event.json().clone());
+        for &format in &[JsonFormat::Compact, JsonFormat::Pretty] {
+            let serialized = event.serialized(format);
+            assert_eq!(packet.encode_with_json(&serialized), packet.encode_with(format));
+            // serialized only once
+            assert!(Arc::ptr_eq(&serialized, &event.serialized(format)));
+        }
+        assert!(!Arc::ptr_eq(&event.serialized(JsonFormat::Compact),
+                             &event.serialized(JsonFormat::Pretty)));
+    }
+
+    #[test]
+    fn event_params() {
+        let (json, binary) = encode_event(Value::String("chunk".to_string()),
+                                          Some(vec![Data::Binary(vec![1, 2, 3])]));
+        assert_eq!(Packet::new_event(None, None, binary.len(), json).encode(),
+                   "51-[\"chunk\",{\"_placeholder\":true,\"num\":0}]");
+        assert_eq!(binary, vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn binary_ack() {
+        let (json, binary) = encode_data(vec![Data::Binary(vec![9, 8])]);
+        let packet = Packet::new_ack(Some("/chat".to_string()), 7, binary.len(), json);
+
+        assert_eq!(packet.encode(),
+                   "61-/chat,7[{\"_placeholder\":true,\"num\":0}]");
+        assert_eq!(binary, vec![vec![9, 8]]);
+    }
+
+    #[test]
+    fn binary_ack_round_trip() {
+        let (json, binary) = encode_data(vec![Data::JSON(Value::Bool(true)),
+                                              Data::Binary(vec![1]),
+                                              Data::Binary(vec![2, 3])]);
+        let header = Packet::new_ack(None, 4, binary.len(), json).encode();
+        assert_eq!(header,
+                   "62-4[true,{\"_placeholder\":true,\"num\":0},\
+                    {\"_placeholder\":true,\"num\":1}]");
+
+        let mut decoded = Packet::from_bytes(header.as_bytes()).expect("Decoding packet");
+        assert_eq!(decoded.opcode, Opcode::BinaryAck);
+        assert_eq!(decoded.id, Some(4));
+        assert!(!decoded.add_attachment(binary[0].clone()));
+        assert!(decoded.add_attachment(binary[1].clone()));
+        assert_eq!(decoded.get_attachments(), Some(vec![vec![1], vec![2, 3]]));
+
+        let (json, binary) = encode_data(vec![Data::JSON(Value::Bool(true))]);
+        assert_eq!(Packet::new_ack(None, 4, binary.len(), json).opcode, Opcode::Ack);
+    }
+
+    #[test]
+    fn compressed_attachments() {
+        let log = b"GET / 200\nGET / 200\nGET / 200\nGET / 200\nGET / 200\n".to_vec();
+        let (json, binary) = encode_data(vec![Data::JSON(Value::String("logs".to_string())),
+                                              Data::Binary(vec![1, 2]),
+                                              Data::CompressedBinary(log.clone())]);
+        let header = Packet::new_event(None, None, binary.len(), json).encode();
+        assert_eq!(header,
+                   "52-[\"logs\",{\"_placeholder\":true,\"num\":0},\
+                    {\"_placeholder\":true,\"compression\":\"lz4\",\"num\":1}]");
+        assert!(binary[1].len() < log.len());
+
+        let mut decoded = Packet::from_bytes(header.as_bytes()).expect("Decoding packet");
+        decoded.add_attachment(binary[0].clone());
+        decoded.add_attachment(binary[1].clone());
+        assert_eq!(decode_attachments(&decoded, None),
+                   Some(vec![vec![1, 2], binary[1].clone()]));
+        assert_eq!(decode_attachments(&decoded, Some(log.len() - 1)),
+                   Some(vec![vec![1, 2], binary[1].clone()]));
+        assert_eq!(decode_attachments(&decoded, Some(log.len())), Some(vec![vec![1, 2], log]));
+    }
+
+    #[test]
+    fn attachment_order() {
+        let blobs = vec![vec![1], vec![2, 2], vec![3, 3, 3]];
+        let (json, binary) = encode_data(vec![Data::JSON(Value::String("files".to_string())),
+                                              Data::Binary(blobs[0].clone()),
+                                              Data::Binary(blobs[1].clone()),
+                                              Data::Binary(blobs[2].clone())]);
+        let header = Packet::new_event(None, Some(1), binary.len(), json).encode();
+
+        let mut decoded = Packet::from_bytes(header.as_bytes()).expect("Decoding packet");
+        assert_eq!(decoded.attachments_num, 3);
+        assert!(!decoded.add_attachment(binary[0].clone()));
+        assert!(!decoded.add_attachment(binary[1].clone()));
+        assert!(decoded.add_attachment(binary[2].clone()));
+        assert_eq!(decoded.get_attachments(), Some(blobs));
+
+        let params = decoded.data.as_ref().unwrap().as_array().unwrap();
+        assert!(!is_placeholder(&params[0]));
+        for (i, placeholder) in params.iter().skip(1).enumerate() {
+            assert!(is_placeholder(placeholder));
+            assert_eq!(placeholder.find("num").and_then(|n| n.as_u64()), Some(i as u64));
+        }
+    }
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use iron::prelude::*;
+use serde_json::Value;
+
+use packet::ProtocolVersion;
+
+/// Details of the HTTP request a client connected with.
+#[derive(Clone, Debug, Default)]
+pub struct Handshake {
+    /// Request headers, keyed by their lowercased names.
+    pub headers: HashMap<String, String>,
+    /// The raw query string of the request URL, if any.
+    pub query: Option<String>,
+    /// The address the request came from. `None` for sockets created
+    /// without a handshake request.
+    pub remote_addr: Option<SocketAddr>,
+    /// The auth payload sent with the client's `Connect` packet. Only
+    /// sent by clients speaking v5 of the protocol.
+    pub auth: Option<Value>,
+}
+
+impl Handshake {
+    #[doc(hidden)]
+    pub fn from_request(req: &Request) -> Handshake {
+        Handshake {
+            headers: req.headers
+                .iter()
+                .map(|h| (h.name().to_lowercase(), h.value_string()))
+                .collect(),
+            query: req.url.clone().into_generic_url().query().map(|q| q.to_string()),
+            remote_addr: Some(req.remote_addr),
+            auth: None,
+        }
+    }
+
+    /// Returns the value of the header `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|v| &v[..])
+    }
+
+    /// Returns the value of the query parameter `name`, if the
+    /// client sent it. The value isn't percent-decoded.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.as_ref().and_then(|q| {
+            q.split('&')
+                .filter_map(|pair| {
+                    let mut kv = pair.splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k == name => Some(v),
+                        _ => None,
+                    }
+                })
+                .next()
+        })
+    }
+
+    /// Returns the query parameters the client sent, percent-decoded,
+    /// with `+` standing for a space. Parameters sent without a value
+    /// map to an empty string. If a parameter was sent several times,
+    /// its first value is kept.
+    pub fn query_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        if let Some(ref query) = self.query {
+            for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+                let mut kv = pair.splitn(2, '=');
+                let key = url_decode(kv.next().unwrap_or(""));
+                let value = url_decode(kv.next().unwrap_or(""));
+                params.entry(key).or_insert(value);
+            }
+        }
+        params
+    }
+
+    /// Returns the WebSocket subprotocols the client offered with the
+    /// `Sec-WebSocket-Protocol` header, in its order of preference.
+    pub fn subprotocols(&self) -> Vec<String> {
+        self.header("sec-websocket-protocol").map_or(vec![], |offered| {
+            offered.split(',')
+                .map(|p| p.trim())
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_string())
+                .collect()
+        })
+    }
+
+    /// Returns the engine.io revision the client asked for with the
+    /// `EIO` query parameter, if it sent a valid one.
+    pub fn engine_io_version(&self) -> Option<usize> {
+        self.query_param("EIO").and_then(|v| v.parse().ok())
+    }
+
+    /// Returns the socket.io revision spoken over the engine.io
+    /// revision the client asked for, see `engine_io_version`. `None`
+    /// if it sent none, or one no socket.io revision is carried over.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.engine_io_version().and_then(ProtocolVersion::from_engine_io_version)
+    }
+
+    /// Returns the transport the request `query` is made over, as
+    /// sent by the client in the `transport` query parameter.
+    #[doc(hidden)]
+    pub fn transport(query: Option<&str>) -> Option<&str> {
+        query.and_then(|q| {
+            q.split('&')
+                .filter(|pair| pair.starts_with("transport="))
+                .map(|pair| &pair[10..])
+                .next()
+        })
+    }
+
+    #[doc(hidden)]
+    pub fn has_session_id(query: Option<&str>) -> bool {
+        query.map_or(false, |q| q.split('&').any(|pair| pair.starts_with("sid=")))
+    }
+}
+
+// Decodes a component of a query string. Malformed escapes are kept
+// as they are, and invalid UTF-8 is replaced.
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() && hex(bytes[i + 1]).is_some() &&
+                    hex(bytes[i + 2]).is_some() => {
+                decoded.push(hex(bytes[i + 1]).unwrap() << 4 | hex(bytes[i + 2]).unwrap());
+                i += 2;
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Handshake;
+    use packet::ProtocolVersion;
+
+    #[test]
+    fn header_ignores_case() {
+        let mut handshake = Handshake::default();
+        handshake.headers.insert("authorization".to_string(), "Bearer abc".to_string());
+
+        assert_eq!(handshake.header("Authorization"), Some("Bearer abc"));
+        assert_eq!(handshake.header("cookie"), None);
+    }
+
+    #[test]
+    fn subprotocols() {
+        let mut handshake = Handshake::default();
+        assert!(handshake.subprotocols().is_empty());
+
+        handshake.headers.insert("sec-websocket-protocol".to_string(),
+                                 "chat.v2, chat.v1,".to_string());
+        assert_eq!(handshake.subprotocols(), vec!["chat.v2".to_string(), "chat.v1".to_string()]);
+    }
+
+    #[test]
+    fn session_id() {
+        assert!(!Handshake::has_session_id(None));
+        assert!(!Handshake::has_session_id(Some("EIO=3&transport=polling")));
+        assert!(Handshake::has_session_id(Some("EIO=3&transport=polling&sid=abc")));
+    }
+
+    #[test]
+    fn query_param() {
+        let mut handshake = Handshake::default();
+        assert_eq!(handshake.query_param("token"), None);
+
+        handshake.query = Some("EIO=3&token=a.1.ff&tokens=x".to_string());
+        assert_eq!(handshake.query_param("token"), Some("a.1.ff"));
+        assert_eq!(handshake.query_param("EI"), None);
+    }
+
+    #[test]
+    fn query_params() {
+        let mut handshake = Handshake::default();
+        assert!(handshake.query_params().is_empty());
+
+        handshake.query = Some("EIO=4&room=caf%C3%A9+au+lait&token=a%2Bb%3D&room=other&flag&bad=%zz%4"
+            .to_string());
+        let params = handshake.query_params();
+        assert_eq!(params.len(), 5);
+        assert_eq!(params["EIO"], "4");
+        assert_eq!(params["room"], "café au lait");
+        assert_eq!(params["token"], "a+b=");
+        assert_eq!(params["flag"], "");
+        assert_eq!(params["bad"], "%zz%4");
+    }
+
+    #[test]
+    fn transport() {
+        assert_eq!(Handshake::transport(None), None);
+        assert_eq!(Handshake::transport(Some("EIO=3&transport=polling")), Some("polling"));
+        assert_eq!(Handshake::transport(Some("transport=websocket&sid=abc")), Some("websocket"));
+    }
+
+    #[test]
+    fn protocol_version() {
+        let mut handshake = Handshake::default();
+        assert_eq!(handshake.engine_io_version(), None);
+        assert_eq!(handshake.protocol_version(), None);
+
+        handshake.query = Some("transport=polling&EIO=4".to_string());
+        assert_eq!(handshake.engine_io_version(), Some(4));
+        assert_eq!(handshake.protocol_version(), Some(ProtocolVersion::V5));
+        handshake.query = Some("EIO=3".to_string());
+        assert_eq!(handshake.engine_io_version(), Some(3));
+        assert_eq!(handshake.protocol_version(), Some(ProtocolVersion::V4));
+        handshake.query = Some("EIO=2".to_string());
+        assert_eq!(handshake.engine_io_version(), Some(2));
+        assert_eq!(handshake.protocol_version(), None);
+        handshake.query = Some("EIO=x&transport=polling".to_string());
+        assert_eq!(handshake.engine_io_version(), None);
+        assert_eq!(handshake.protocol_version(), None);
+    }
+}
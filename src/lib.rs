@@ -1,11 +1,24 @@
 extern crate engine_io;
+extern crate hmac;
 extern crate iron;
+extern crate lz4_flex;
 extern crate serde;
 extern crate serde_json;
+extern crate sha2;
 
 pub mod server;
 pub mod socket;
 pub mod data;
-mod packet;
+pub mod namespace;
+pub mod adapter;
+pub mod handshake;
+pub mod packet;
+pub mod traffic;
+mod compress;
+mod rate_limit;
+mod session;
 
+pub use packet::{Error, JsonFormat, ProtocolVersion};
+
+/// The protocol revision spoken by default, see `ProtocolVersion`.
 pub const PROTOCOL_VERSION: usize = 4;
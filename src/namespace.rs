@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+use adapter::{Adapter, BroadcastOperator};
+use data::{encode_event, Data};
+use socket::{Socket, DisconnectReason, SendError};
+use serde_json::Value;
+
+/// Name of the namespace clients are connected to before sending a
+/// `Connect` packet of their own.
+pub const DEFAULT_NAMESPACE: &'static str = "/";
+
+/// A namespace clients can connect to, obtained with `Server::of`.
+// `C` is only ever `Socket` outside of tests.
+#[derive(Clone)]
+pub struct Namespace<C = Socket> {
+    name: String,
+    clients: Arc<RwLock<Vec<C>>>,
+    adapter: Arc<Adapter>,
+    middleware: Arc<RwLock<Vec<Arc<Fn(&C) -> Result<(), Value>>>>>,
+    default_callbacks: Arc<RwLock<HashMap<String, Arc<Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data>>>>>,
+}
+
+unsafe impl<C> Send for Namespace<C> {}
+unsafe impl<C> Sync for Namespace<C> {}
+
+impl<C> Namespace<C> {
+    #[doc(hidden)]
+    pub fn new(name: String, adapter: Arc<Adapter>) -> Namespace<C> {
+        Namespace {
+            name: name,
+            clients: Arc::new(RwLock::new(vec![])),
+            adapter: adapter,
+            middleware: Arc::new(RwLock::new(vec![])),
+            default_callbacks: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    #[inline(always)]
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Returns whether a client is connected to this namespace, or it
+    /// has middleware or default handlers set.
+    #[doc(hidden)]
+    pub fn is_active(&self) -> bool {
+        !self.clients.read().unwrap().is_empty() || !self.middleware.read().unwrap().is_empty() ||
+        !self.default_callbacks.read().unwrap().is_empty()
+    }
+
+    /// Add a middleware run when a client asks to connect to this
+    /// namespace, before it's connected. Middleware run in the order
+    /// they were added; the first to return an error rejects the
+    /// client with a connect error carrying it, and the rest don't run.
+    /// The client's auth payload is available through `Socket::auth`.
+    ///
+    /// v4 clients are put in the default namespace without asking, so
+    /// middleware of the default namespace only runs for them if they
+    /// send a `Connect` packet for it.
+    pub fn use_middleware<F>(&self, f: F)
+        where F: Fn(&C) -> Result<(), Value> + 'static
+    {
+        self.middleware.write().unwrap().push(Arc::new(f));
+    }
+
+    #[doc(hidden)]
+    pub fn run_middleware(&self, so: &C) -> Result<(), Value> {
+        run_chain(&self.middleware, so)
+    }
+
+    /// Set a default callback for `event`, installed on every socket
+    /// connecting to this namespace before it processes any packet
+    /// sent to the namespace. Handlers registered on the socket, with
+    /// `Socket::on` or `Server::on_event`, take precedence.
+    pub fn on_event<F>(&self, event: String, f: F)
+        where F: Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data> + 'static
+    {
+        self.default_callbacks.write().unwrap().insert(event, Arc::new(f));
+    }
+}
+
+impl Namespace {
+    #[doc(hidden)]
+    pub fn install_callbacks(&self, so: &Socket) {
+        install_defaults(so, &self.default_callbacks.read().unwrap());
+    }
+
+    /// Emits an event with the value `event` and parameters
+    /// `params` to all clients connected to this namespace,
+    /// returning the number of clients the event was sent to.
+    pub fn emit(&self, event: Value, params: Option<Vec<Data>>) -> usize {
+        self.emit_except(None, event, params)
+    }
+
+    /// Returns a `BroadcastOperator` emitting to every client in
+    /// `room` of this namespace.
+    pub fn to(&self, room: &str) -> BroadcastOperator {
+        BroadcastOperator::new(self.adapter.clone())
+            .in_namespace(Some(self.name.clone()))
+            .to(room)
+    }
+
+    /// Returns a `NamespaceBroadcast` emitting to every client
+    /// connected to this namespace, except the one with the id
+    /// `sender_id`. Use this to relay a client's message to the rest
+    /// of the namespace.
+    pub fn broadcast_from(&self, sender_id: &str) -> NamespaceBroadcast {
+        NamespaceBroadcast {
+            namespace: self.clone(),
+            except: sender_id.to_string(),
+        }
+    }
+
+    fn emit_except(&self, except: Option<&str>, event: Value, params: Option<Vec<Data>>) -> usize {
+        let (json, attachments) = encode_event(event, params);
+        emit_encoded(&self.clients.read().unwrap(), except, &json, &attachments)
+    }
+
+    /// Calls `f` on every client connected to this namespace.
+    ///
+    /// The namespace's client list is locked while iterating, so `f`
+    /// must not connect clients to or disconnect them from this
+    /// namespace, e.g. by calling `Socket::close`.
+    pub fn for_each_socket<F>(&self, f: F)
+        where F: FnMut(&Socket)
+    {
+        for_each_client(&self.clients, f);
+    }
+
+    #[doc(hidden)]
+    pub fn add_socket(&self, so: Socket) {
+        let mut clients = self.clients.write().unwrap();
+        if !clients.iter().any(|s| s.id() == so.id()) {
+            clients.push(so);
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn remove_socket(&self, id: &str) {
+        let mut clients = self.clients.write().unwrap();
+        clients.retain(|s| s.id() != id);
+    }
+
+    /// Disconnect all clients connected to this namespace. Clients
+    /// are only ever connected to a single namespace, so this closes
+    /// their connection.
+    pub fn disconnect_all(&self, reason: DisconnectReason) {
+        disconnect_clients(&self.clients, reason);
+    }
+}
+
+// Runs the middleware in `chain` in order, stopping at the first
+// error. The chain is copied first, so middleware can add middleware
+// of their own.
+fn run_chain<T>(chain: &RwLock<Vec<Arc<Fn(&T) -> Result<(), Value>>>>,
+                so: &T)
+                -> Result<(), Value> {
+    let middleware = chain.read().unwrap().clone();
+    for f in middleware {
+        try!(f(so));
+    }
+    Ok(())
+}
+
+// What a namespace needs of its clients.
+trait Client {
+    fn id(&self) -> String;
+    fn try_emit_encoded(&self, json: Value, attachments: Vec<Vec<u8>>) -> Result<(), SendError>;
+    fn disconnect(&self, reason: DisconnectReason);
+    fn set_namespace_callbacks(&self,
+                               defaults: Vec<(String, Arc<Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data>>)>);
+}
+
+impl Client for Socket {
+    fn id(&self) -> String {
+        Socket::id(self)
+    }
+
+    fn try_emit_encoded(&self, json: Value, attachments: Vec<Vec<u8>>) -> Result<(), SendError> {
+        Socket::try_emit_encoded(self, json, attachments)
+    }
+
+    fn disconnect(&self, reason: DisconnectReason) {
+        Socket::disconnect(self, reason)
+    }
+
+    fn set_namespace_callbacks(&self,
+                               defaults: Vec<(String, Arc<Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data>>)>) {
+        Socket::set_namespace_callbacks(self, defaults)
+    }
+}
+
+fn install_defaults<C: Client>(so: &C,
+                               defaults: &HashMap<String, Arc<Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data>>>) {
+    so.set_namespace_callbacks(defaults.iter().map(|(e, f)| (e.clone(), f.clone())).collect());
+}
+
+// Emits an encoded event to every client but `except`, returning the
+// number of clients it was sent to.
+fn emit_encoded<C: Client>(clients: &[C],
+                           except: Option<&str>,
+                           json: &Value,
+                           attachments: &[Vec<u8>])
+                           -> usize {
+    clients.iter()
+        .filter(|s| Some(&s.id()[..]) != except)
+        .filter(|s| s.try_emit_encoded(json.clone(), attachments.to_vec()).is_ok())
+        .count()
+}
+
+// The list is emptied before disconnecting anyone, since disconnected
+// clients remove themselves from their namespace.
+fn disconnect_clients<C: Client>(clients: &RwLock<Vec<C>>, reason: DisconnectReason) {
+    let clients = mem::replace(&mut *clients.write().unwrap(), vec![]);
+    for so in clients {
+        so.disconnect(reason);
+    }
+}
+
+fn for_each_client<C, F>(clients: &RwLock<Vec<C>>, mut f: F)
+    where F: FnMut(&C)
+{
+    let clients = clients.read().unwrap();
+    for so in clients.iter() {
+        f(so);
+    }
+}
+
+/// Emits events to every client in a namespace except one, obtained
+/// with `Namespace::broadcast_from`.
+pub struct NamespaceBroadcast {
+    namespace: Namespace,
+    except: String,
+}
+
+impl NamespaceBroadcast {
+    /// Emits an event with the value `event` and parameters `params`,
+    /// returning the number of clients the event was sent to.
+    pub fn emit(&self, event: Value, params: Option<Vec<Data>>) -> usize {
+        self.namespace.emit_except(Some(&self.except), event, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, RwLock};
+
+    use super::{disconnect_clients, emit_encoded, for_each_client, install_defaults, run_chain,
+                Client, Namespace};
+    use adapter::MemoryAdapter;
+    use data::{encode_event, Data};
+    use socket::{DisconnectReason, SendError};
+    use serde_json::Value;
+
+    // Records what's done to it in a log shared by all clients.
+    #[derive(Clone)]
+    struct TestClient {
+        id: &'static str,
+        closed: bool,
+        log: Rc<RefCell<Vec<String>>>,
+        // the namespace's client list, which closed sockets remove
+        // themselves from
+        list: Arc<RwLock<Vec<TestClient>>>,
+    }
+
+    impl Client for TestClient {
+        fn id(&self) -> String {
+            self.id.to_string()
+        }
+
+        fn try_emit_encoded(&self, json: Value, attachments: Vec<Vec<u8>>)
+                            -> Result<(), SendError> {
+            if self.closed {
+                return Err(SendError::Closed);
+            }
+            self.log.borrow_mut().push(format!("{} {} {:?}", self.id, json, attachments));
+            Ok(())
+        }
+
+        fn disconnect(&self, reason: DisconnectReason) {
+            self.log.borrow_mut().push(format!("{} {:?}", self.id, reason));
+            let mut list = self.list.try_write().expect("client list still locked");
+            list.retain(|c| c.id != self.id);
+        }
+
+        fn set_namespace_callbacks(&self,
+                                   defaults: Vec<(String, Arc<Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data>>)>) {
+            let mut defaults: Vec<String> = defaults.into_iter()
+                .map(|(event, f)| {
+                    let reply: Vec<String> = f(vec![Value::Bool(true)], None)
+                        .into_iter()
+                        .map(|d| match d {
+                            Data::JSON(v) => v.to_string(),
+                            _ => "binary".to_string(),
+                        })
+                        .collect();
+                    format!("{} [{}]", event, reply.join(","))
+                })
+                .collect();
+            defaults.sort();
+            self.log.borrow_mut().extend(defaults);
+        }
+    }
+
+    fn clients(ids: &[&'static str]) -> (Arc<RwLock<Vec<TestClient>>>, Rc<RefCell<Vec<String>>>) {
+        let list = Arc::new(RwLock::new(vec![]));
+        let log = Rc::new(RefCell::new(vec![]));
+        for &id in ids {
+            list.write().unwrap().push(TestClient {
+                id: id,
+                closed: false,
+                log: log.clone(),
+                list: list.clone(),
+            });
+        }
+        (list, log)
+    }
+
+    fn namespace() -> Namespace {
+        Namespace::new("/chat".to_string(), Arc::new(MemoryAdapter::new()))
+    }
+
+    #[test]
+    fn active() {
+        let nsp = namespace();
+        assert!(!nsp.is_active());
+        nsp.use_middleware(|_| Ok(()));
+        assert!(nsp.is_active());
+
+        let nsp = namespace();
+        nsp.on_event("ping".to_string(), |_, _| vec![]);
+        assert!(nsp.is_active());
+    }
+
+    #[test]
+    fn middleware() {
+        let chain: Arc<RwLock<Vec<Arc<Fn(&Vec<String>) -> Result<(), Value>>>>> =
+            Arc::new(RwLock::new(vec![]));
+        assert_eq!(run_chain(&chain, &vec![]), Ok(()));
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let (l1, l2, l3) = (log.clone(), log.clone(), log.clone());
+        chain.write().unwrap().push(Arc::new(move |_| {
+            l1.borrow_mut().push("auth");
+            Ok(())
+        }));
+        chain.write().unwrap().push(Arc::new(move |roles| {
+            l2.borrow_mut().push("admin");
+            if roles.iter().any(|r| r == "admin") {
+                Ok(())
+            } else {
+                Err(Value::String("not an admin".to_string()))
+            }
+        }));
+        chain.write().unwrap().push(Arc::new(move |_| {
+            l3.borrow_mut().push("log");
+            Ok(())
+        }));
+
+        assert_eq!(run_chain(&chain, &vec!["admin".to_string()]), Ok(()));
+        assert_eq!(*log.borrow(), vec!["auth", "admin", "log"]);
+
+        // the first error rejects the client, and the rest don't run
+        log.borrow_mut().clear();
+        assert_eq!(run_chain(&chain, &vec![]), Err(Value::String("not an admin".to_string())));
+        assert_eq!(*log.borrow(), vec!["auth", "admin"]);
+    }
+
+    #[test]
+    fn middleware_adding_middleware() {
+        let chain: Arc<RwLock<Vec<Arc<Fn(&()) -> Result<(), Value>>>>> =
+            Arc::new(RwLock::new(vec![]));
+        let runs = Rc::new(RefCell::new(0));
+        let (c, r) = (chain.clone(), runs.clone());
+        chain.write().unwrap().push(Arc::new(move |_| {
+            let r = r.clone();
+            c.write().unwrap().push(Arc::new(move |_| {
+                *r.borrow_mut() += 1;
+                Ok(())
+            }));
+            Ok(())
+        }));
+
+        assert_eq!(run_chain(&chain, &()), Ok(()));
+        // added middleware only runs for the next client
+        assert_eq!(*runs.borrow(), 0);
+        assert_eq!(run_chain(&chain, &()), Ok(()));
+        assert_eq!(*runs.borrow(), 1);
+        assert_eq!(chain.read().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn use_middleware() {
+        let adapter = Arc::new(MemoryAdapter::new());
+        let admin: Namespace<TestClient> = Namespace::new("/admin".to_string(), adapter.clone());
+        let public: Namespace<TestClient> = Namespace::new("/public".to_string(), adapter);
+        let (list, log) = clients(&["a", "b"]);
+        let (a, b) = (list.read().unwrap()[0].clone(), list.read().unwrap()[1].clone());
+
+        let l = log.clone();
+        admin.use_middleware(move |so| {
+            l.borrow_mut().push(format!("{} auth", so.id));
+            Ok(())
+        });
+        let l = log.clone();
+        admin.use_middleware(move |so| {
+            l.borrow_mut().push(format!("{} admin", so.id));
+            if so.id == "a" {
+                Ok(())
+            } else {
+                Err(Value::String("not an admin".to_string()))
+            }
+        });
+        let l = log.clone();
+        public.use_middleware(move |so| {
+            l.borrow_mut().push(format!("{} public", so.id));
+            Ok(())
+        });
+
+        assert_eq!(admin.run_middleware(&a), Ok(()));
+        assert_eq!(*log.borrow(), vec!["a auth", "a admin"]);
+
+        // a client `/admin` rejects is still let into `/public`
+        log.borrow_mut().clear();
+        assert_eq!(public.run_middleware(&b), Ok(()));
+        assert_eq!(*log.borrow(), vec!["b public"]);
+
+        log.borrow_mut().clear();
+        assert_eq!(admin.run_middleware(&b), Err(Value::String("not an admin".to_string())));
+        assert_eq!(*log.borrow(), vec!["b auth", "b admin"]);
+    }
+
+    #[test]
+    fn default_handlers() {
+        let nsp = namespace();
+        let (list, log) = clients(&["a"]);
+        let so = list.read().unwrap()[0].clone();
+        install_defaults(&so, &nsp.default_callbacks.read().unwrap());
+        assert!(log.borrow().is_empty());
+
+        nsp.on_event("ping".to_string(),
+                     |_, _| vec![Data::JSON(Value::String("pong".to_string()))]);
+        nsp.on_event("echo".to_string(), |args, _| args.into_iter().map(Data::JSON).collect());
+        install_defaults(&so, &nsp.default_callbacks.read().unwrap());
+        assert_eq!(*log.borrow(), vec!["echo [true]", "ping [\"pong\"]"]);
+
+        // setting a handler for the same event replaces it
+        log.borrow_mut().clear();
+        nsp.on_event("ping".to_string(), |_, _| vec![]);
+        install_defaults(&so, &nsp.default_callbacks.read().unwrap());
+        assert_eq!(*log.borrow(), vec!["echo [true]", "ping []"]);
+    }
+
+    #[test]
+    fn for_each_socket() {
+        let mut visited = vec![];
+        namespace().for_each_socket(|so| visited.push(so.id()));
+        assert!(visited.is_empty());
+
+        let clients = RwLock::new(vec!["a", "b", "c"]);
+        let mut visited = vec![];
+        for_each_client(&clients, |&id| visited.push(id));
+        assert_eq!(visited, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn disconnect_all() {
+        namespace().disconnect_all(DisconnectReason::ServerDisconnect);
+
+        let (list, log) = clients(&["a", "b"]);
+        // the clients of another namespace
+        let (other, other_log) = clients(&["c", "d"]);
+        disconnect_clients(&list, DisconnectReason::ServerDisconnect);
+        assert_eq!(*log.borrow(), vec!["a ServerDisconnect", "b ServerDisconnect"]);
+        assert!(list.read().unwrap().is_empty());
+        let ids: Vec<&str> = other.read().unwrap().iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec!["c", "d"]);
+        assert!(other_log.borrow().is_empty());
+
+        disconnect_clients(&list, DisconnectReason::IdleTimeout);
+        assert_eq!(log.borrow().len(), 2);
+        assert!(other_log.borrow().is_empty());
+    }
+
+    #[test]
+    fn broadcast_from() {
+        let nsp = namespace();
+        assert_eq!(nsp.emit(Value::String("news".to_string()), None), 0);
+        assert_eq!(nsp.broadcast_from("a").emit(Value::String("news".to_string()), None), 0);
+
+        let (list, log) = clients(&["a", "b", "c", "d"]);
+        list.write().unwrap()[3].closed = true;
+        let (json, attachments) = encode_event(Value::String("chat".to_string()),
+                                               Some(vec![Data::Binary(vec![1])]));
+        let list = list.read().unwrap();
+
+        assert_eq!(emit_encoded(&list, Some("a"), &json, &attachments), 2);
+        assert_eq!(*log.borrow(),
+                   vec!["b [\"chat\",{\"_placeholder\":true,\"num\":0}] [[1]]",
+                        "c [\"chat\",{\"_placeholder\":true,\"num\":0}] [[1]]"]);
+
+        log.borrow_mut().clear();
+        assert_eq!(emit_encoded(&list, None, &json, &[]), 3);
+        assert_eq!(log.borrow().len(), 3);
+        // excluding an id no client has excludes no one
+        assert_eq!(emit_encoded(&list, Some("x"), &json, &[]), 3);
+    }
+}
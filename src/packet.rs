@@ -1,12 +1,17 @@
+use std::cell::Cell;
 use std::mem;
+use std::fmt;
+use std::error;
 use std::string::FromUtf8Error;
 use std::convert::From;
 use std::iter::Peekable;
 
-use serde_json::ser::to_string;
-use serde_json::de::from_str;
+use serde_json::ser::{to_writer, to_writer_pretty};
+use serde::Deserialize;
+use serde_json::de::Deserializer;
 use serde_json::error::Error as JSONError;
 use serde_json::Value;
+use serde_json::builder::ObjectBuilder;
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Opcode {
@@ -19,11 +24,77 @@ pub enum Opcode {
     BinaryAck = '6' as isize,
 }
 
+/// How JSON payloads are serialized when encoding packets. Numbers
+/// keep their representation either way: integers are never
+/// converted to floats, and floats are written with enough digits
+/// to be read back exactly.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum JsonFormat {
+    Compact,
+    Pretty,
+}
+
+impl Default for JsonFormat {
+    fn default() -> JsonFormat {
+        JsonFormat::Compact
+    }
+}
+
+/// The socket.io protocol revision spoken with clients.
+///
+/// In v5, clients send a `Connect` packet for every namespace they
+/// join, optionally carrying an auth payload, and the server answers
+/// it with `{"sid": ...}`. Connect errors are always objects with a
+/// `message`, and `Error` packets are only used to reject
+/// connections.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ProtocolVersion {
+    V4,
+    V5,
+}
+
+impl ProtocolVersion {
+    /// Returns the socket.io revision number. This isn't the `EIO`
+    /// query parameter sent by clients, which is the revision of the
+    /// underlying engine.io protocol.
+    pub fn number(&self) -> usize {
+        match *self {
+            ProtocolVersion::V4 => 4,
+            ProtocolVersion::V5 => 5,
+        }
+    }
+
+    /// Returns the revision of the engine.io protocol it's carried
+    /// over, as sent by clients in the `EIO` query parameter.
+    pub fn engine_io_version(&self) -> usize {
+        match *self {
+            ProtocolVersion::V4 => 3,
+            ProtocolVersion::V5 => 4,
+        }
+    }
+
+    /// Returns the revision carried over the engine.io revision
+    /// `version`, or `None` if there's none.
+    pub fn from_engine_io_version(version: usize) -> Option<ProtocolVersion> {
+        match version {
+            3 => Some(ProtocolVersion::V4),
+            4 => Some(ProtocolVersion::V5),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> ProtocolVersion {
+        ProtocolVersion::V4
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Packet {
     pub namespace: Option<String>,
     pub opcode: Opcode,
-    pub id: Option<usize>,
+    pub id: Option<u64>,
     /// Number of attachments
     pub attachments_num: usize,
     pub data: Option<Value>,
@@ -40,6 +111,71 @@ pub enum Error {
     NoEvent,
     AckIDMissing,
     NonBinaryHasAttachments,
+    /// The handler registered for the given event panicked.
+    HandlerPanicked(String),
+    /// The client sent packets faster than its rate limit allows.
+    RateLimited,
+    /// The packet's data was followed by something other than
+    /// whitespace, starting at the given byte of the packet.
+    TrailingData(usize),
+    /// No handler is registered for the given event, and the socket
+    /// only accepts registered events.
+    UnknownEvent(String),
+    /// The client sent a message of the given number of bytes, larger
+    /// than the socket's limit.
+    MessageTooLarge(usize),
+}
+
+impl Error {
+    /// Returns a short code identifying the kind of error, sent to
+    /// the client in error packets.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::InvalidOpcode(_) => "INVALID_OPCODE",
+            Error::InvalidPacket => "INVALID_PACKET",
+            Error::PacketDataNotArray => "DATA_NOT_ARRAY",
+            Error::JSONError(_) => "INVALID_JSON",
+            Error::FromUtf8Error(_) => "INVALID_UTF8",
+            Error::NoEvent => "NO_EVENT",
+            Error::AckIDMissing => "ACK_ID_MISSING",
+            Error::NonBinaryHasAttachments => "UNEXPECTED_ATTACHMENTS",
+            Error::HandlerPanicked(_) => "HANDLER_PANICKED",
+            Error::RateLimited => "RATE_LIMITED",
+            Error::TrailingData(_) => "TRAILING_DATA",
+            Error::UnknownEvent(_) => "UNKNOWN_EVENT",
+            Error::MessageTooLarge(_) => "MESSAGE_TOO_LARGE",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidOpcode(c) => write!(f, "invalid opcode {:?}", c as char),
+            Error::InvalidPacket => write!(f, "invalid packet"),
+            Error::PacketDataNotArray => write!(f, "packet data is not an array"),
+            Error::JSONError(ref e) => write!(f, "invalid JSON: {}", e),
+            Error::FromUtf8Error(ref e) => write!(f, "invalid UTF-8: {}", e),
+            Error::NoEvent => write!(f, "event packet has no event name"),
+            Error::AckIDMissing => write!(f, "ack packet has no id"),
+            Error::NonBinaryHasAttachments => write!(f, "non-binary packet has attachments"),
+            Error::HandlerPanicked(ref event) => write!(f, "handler for event {:?} panicked", event),
+            Error::RateLimited => write!(f, "rate limit exceeded"),
+            Error::TrailingData(pos) => write!(f, "trailing data at byte {}", pos),
+            Error::UnknownEvent(ref event) => write!(f, "no handler for event {:?}", event),
+            Error::MessageTooLarge(len) => write!(f, "message of {} bytes is too large", len),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            Error::JSONError(ref e) => Some(e),
+            Error::FromUtf8Error(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<JSONError> for Error {
@@ -56,7 +192,7 @@ impl From<FromUtf8Error> for Error {
 
 impl Packet {
     pub fn new_event(namespace: Option<String>,
-                     id: Option<usize>,
+                     id: Option<u64>,
                      attachments_num: usize,
                      params: Value)
                      -> Packet {
@@ -75,20 +211,64 @@ impl Packet {
         }
     }
 
+    /// Returns an `Error` packet for `error`, with the payload
+    /// `{"code": error.code(), "message": error.to_string()}`.
     pub fn new_error(namespace: Option<String>,
                      error: Error) -> Packet {
+        Packet::new_error_payload(namespace,
+                                  ObjectBuilder::new()
+                                      .insert("code", error.code())
+                                      .insert("message", error.to_string())
+                                      .build())
+    }
+    
+    /// Returns a `Connect` packet for `namespace`. v5 servers answer
+    /// connects with `{"sid": ...}` as the payload.
+    pub fn new_connect(namespace: Option<String>, data: Option<Value>) -> Packet {
+        Packet {
+            namespace: namespace,
+            attachments_num: 0,
+            opcode: Opcode::Connect,
+            id: None,
+            data: data,
+            attachments: None,
+        }
+    }
+
+    pub fn new_disconnect(namespace: Option<String>) -> Packet {
+        Packet {
+            namespace: namespace,
+            attachments_num: 0,
+            opcode: Opcode::Disconnect,
+            id: None,
+            data: None,
+            attachments: None,
+        }
+    }
+
+    /// Returns an `Error` packet rejecting a connection to `namespace`,
+    /// carrying `data` as its payload. Encodes as
+    /// `4/nsp,{"message":"..."}`, which clients surface as a
+    /// `connect_error`.
+    #[inline(always)]
+    pub fn new_connect_error(namespace: Option<String>, data: Value) -> Packet {
+        Packet::new_error_payload(namespace, data)
+    }
+
+    /// Returns an `Error` packet carrying `data` as its payload.
+    pub fn new_error_payload(namespace: Option<String>, data: Value) -> Packet {
         Packet {
             namespace: namespace,
             attachments_num: 0,
             opcode: Opcode::Error,
             id: None,
-            data: Some(Value::String(format!("{:?}", error))),
+            data: Some(data),
             attachments: None,
         }
     }
-    
+
     pub fn new_ack(namespace: Option<String>,
-                   id: usize,
+                   id: u64,
                    attachments_num: usize,
                    params: Value)
                    -> Packet {
@@ -106,6 +286,29 @@ impl Packet {
         }
     }
 
+    /// Compares the namespace, opcode, id, number of attachments and
+    /// data of two packets. Unlike `==`, attachments that have been
+    /// received for either packet are not compared.
+    pub fn semantic_eq(&self, other: &Packet) -> bool {
+        self.namespace == other.namespace && self.opcode == other.opcode &&
+        self.id == other.id && self.attachments_num == other.attachments_num &&
+        self.data == other.data
+    }
+
+    /// Sets the number of attachments following the packet, switching
+    /// between the plain and binary opcodes as needed.
+    #[doc(hidden)]
+    pub fn set_attachments_num(&mut self, num: usize) {
+        self.attachments_num = num;
+        self.opcode = match (self.opcode, num) {
+            (Opcode::Event, n) if n != 0 => Opcode::BinaryEvent,
+            (Opcode::BinaryEvent, 0) => Opcode::Event,
+            (Opcode::Ack, n) if n != 0 => Opcode::BinaryAck,
+            (Opcode::BinaryAck, 0) => Opcode::Ack,
+            (opcode, _) => opcode,
+        };
+    }
+
     #[doc(hidden)]
     pub fn add_attachment(&mut self, bytes: Vec<u8>) -> bool {
         if self.attachments.is_none() {
@@ -123,17 +326,46 @@ impl Packet {
         self.attachments.clone()
     }
 
+    /// Returns the number of attachments received so far.
+    #[inline]
+    pub fn attachments_received(&self) -> usize {
+        self.attachments.as_ref().map_or(0, |a| a.len())
+    }
+
+    /// Returns `true` if the packet expects or carries attachments.
+    /// This is decided by the number of attachments alone, a binary
+    /// opcode announcing zero attachments carries none.
     #[doc(hidden)]
     #[inline(always)]
     pub fn has_attachments(&self) -> bool {
-        self.attachments.is_some() || self.attachments_num != 0
+        self.attachments_num != 0 ||
+        self.attachments.as_ref().map_or(false, |a| !a.is_empty())
+    }
+
+    /// Like `from_bytes`, but ignores ASCII whitespace, such as a
+    /// trailing CRLF, around the whole packet, for clients and proxies
+    /// adding it. `from_bytes` only allows whitespace after a JSON
+    /// payload, and otherwise takes it to be part of the namespace or
+    /// payload. Positions in errors are relative to the trimmed packet.
+    pub fn from_bytes_lenient(bytes: &[u8]) -> Result<Packet, Error> {
+        let is_space = |b: &u8| (*b as char).is_whitespace() && b.is_ascii();
+        let start = bytes.iter().position(|b| !is_space(b)).unwrap_or(bytes.len());
+        let end = bytes.iter().rposition(|b| !is_space(b)).map_or(start, |i| i + 1);
+        Packet::from_bytes(&bytes[start..end])
     }
 
+    /// Decodes a single packet. Anything after a `Connect` or `Error`
+    /// packet's header is parsed as its JSON payload, so whitespace
+    /// there, e.g. the newline of `0\n`, fails to decode as invalid
+    /// JSON, as does anything but whitespace after a JSON payload.
+    /// Use `from_bytes_lenient` for peers that add whitespace.
     pub fn from_bytes(bytes: &[u8]) -> Result<Packet, Error> {
         let mut chars: Peekable<_> = bytes.iter().peekable();
 
         let opcode: Opcode = match chars.next() {
-            Some(c) if *c > (Opcode::BinaryAck as u8) => return Err(Error::InvalidOpcode(*c as u8)),
+            Some(c) if *c < (Opcode::Connect as u8) || *c > (Opcode::BinaryAck as u8) => {
+                return Err(Error::InvalidOpcode(*c as u8))
+            }
             Some(c) => unsafe { mem::transmute(*c as u8) },
             None => return Err(Error::InvalidPacket),
         };
@@ -164,12 +396,17 @@ impl Packet {
             None
         };
 
-        let mut id: usize = 0;
+        // ids are u64 rather than usize so the ids accepted don't
+        // depend on the target.
+        let mut id: u64 = 0;
         let mut has_id = false;
 
         loop {
             if chars.peek().map_or(false, |ch: &&u8| **ch >= b'0' && **ch <= b'9') {
-                id = id * 10 + (*chars.next().unwrap() as char).to_digit(10).unwrap() as usize;
+                let digit = (*chars.next().unwrap() as char).to_digit(10).unwrap() as u64;
+                id = try!(id.checked_mul(10)
+                    .and_then(|id| id.checked_add(digit))
+                    .ok_or(Error::InvalidPacket));
                 has_id = true;
             } else {
                 break;
@@ -178,8 +415,9 @@ impl Packet {
 
         let data: Option<Value> = match opcode {
             Opcode::Event | Opcode::BinaryEvent | Opcode::Ack | Opcode::BinaryAck => {
+                let offset = bytes.len() - chars.len();
                 let js = try!(String::from_utf8(chars.map(|c| *c).collect()));
-                let parsed: Value = try!(from_str(&js));
+                let parsed = try!(parse_json(&js, offset));
 
                 if (opcode == Opcode::Event || opcode == Opcode::BinaryEvent) &&
                    !parsed.is_array() {
@@ -192,12 +430,25 @@ impl Packet {
                     && attachments_num != 0 {
                         return Err(Error::NonBinaryHasAttachments);
                     }
-                if parsed.as_array().unwrap().is_empty() {
+                // events need at least the event name, while acks
+                // may carry no arguments at all.
+                if (opcode == Opcode::Event || opcode == Opcode::BinaryEvent) &&
+                   parsed.as_array().unwrap().is_empty() {
                     return Err(Error::NoEvent);
                 }
 
                 Some(parsed)
             }
+            // v5 clients may send an auth payload with connects
+            Opcode::Error | Opcode::Connect => {
+                let offset = bytes.len() - chars.len();
+                let js = try!(String::from_utf8(chars.map(|c| *c).collect()));
+                if js.is_empty() {
+                    None
+                } else {
+                    Some(try!(parse_json(&js, offset)))
+                }
+            }
             _ => None,
         };
 
@@ -215,44 +466,275 @@ impl Packet {
         })
     }
 
+    #[inline(always)]
     pub fn encode(&self) -> String {
-        let mut s = String::new();
-        let mut nsp = false;
+        self.encode_with(JsonFormat::Compact)
+    }
 
-        s.push((self.opcode as u8) as char);
-        if self.attachments_num != 0 {
-            s.push_str(&self.attachments_num.to_string());
-            s.push('-');
+    pub fn encode_with(&self, format: JsonFormat) -> String {
+        let mut buf = self.encode_header(self.data.is_some());
+        // the data is serialized straight into the packet's buffer,
+        // rather than into a string of its own copied after the header.
+        if let Some(ref data) = self.data {
+            match format {
+                JsonFormat::Compact => to_writer(&mut buf, data),
+                JsonFormat::Pretty => to_writer_pretty(&mut buf, data),
+            }.unwrap();
+        }
+        String::from_utf8(buf).unwrap()
+    }
+
+    /// Encodes the packet like `encode_with`, with `json`, data
+    /// already serialized, in place of its own data, so events sent to
+    /// many clients are only serialized once.
+    #[doc(hidden)]
+    pub fn encode_with_json(&self, json: &str) -> String {
+        let mut buf = self.encode_header(true);
+        buf.extend_from_slice(json.as_bytes());
+        String::from_utf8(buf).unwrap()
+    }
+
+    // Encodes everything before the packet's data: the opcode,
+    // attachment count, namespace and id, followed by the comma
+    // separating the namespace from the data if `data` is set.
+    fn encode_header(&self, data: bool) -> Vec<u8> {
+        let attachments_num = if self.attachments_num != 0 {
+            Some(self.attachments_num.to_string())
+        } else {
+            None
+        };
+        let id = self.id.map(|id| id.to_string());
+        let comma = self.namespace.is_some() && (id.is_some() || data);
+
+        let mut capacity = 1;
+        if let Some(ref n) = attachments_num {
+            capacity += n.len() + 1;
+        }
+        capacity += self.namespace.as_ref().map_or(0, |n| n.len());
+        capacity += id.as_ref().map_or(0, |id| id.len());
+        if comma {
+            capacity += 1;
+        }
+        let mut buf = Vec::with_capacity(capacity);
+
+        buf.push(self.opcode as u8);
+        if let Some(n) = attachments_num {
+            buf.extend_from_slice(n.as_bytes());
+            buf.push(b'-');
         }
         if let Some(ref n) = self.namespace {
-            s.push_str(n);
-            nsp = true;
+            buf.extend_from_slice(n.as_bytes());
+        }
+        if comma {
+            buf.push(b',');
         }
+        if let Some(id) = id {
+            buf.extend_from_slice(id.as_bytes());
+        }
+        buf
+    }
+}
 
-        if let Some(id) = self.id {
-            if nsp {
-                s.push(',');
-                nsp = false;
+/// Decodes packets from a stream of bytes delivered in chunks, e.g.
+/// by a transport fragmenting messages.
+///
+/// A packet carrying an array or object ends with its payload, so
+/// such packets are decoded as soon as their last chunk is fed.
+/// Connect, disconnect and error packets carry no id, so one without
+/// a payload ends where a digit starts the next packet. Other packets
+/// have no end of their own, and are only decoded by `finish`, once
+/// the transport reports the end of the message. That includes a
+/// packet with a namespace but neither an id nor a payload, as its
+/// namespace only ends at the next comma.
+#[derive(Default, Debug)]
+pub struct PacketDecoder {
+    buf: Vec<u8>,
+    // how far into `buf` the current packet has been scanned
+    scanned: usize,
+    scan: Scan,
+    opcode: u8,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+/// Returned by `PacketDecoder::feed` when some of the packets a chunk
+/// completed failed to decode.
+#[derive(Debug)]
+pub struct FeedError {
+    /// The packets that did decode, in order.
+    pub packets: Vec<Packet>,
+    /// Why each of the other packets failed to decode, in order.
+    pub errors: Vec<Error>,
+}
+
+// The part of a packet `PacketDecoder` is scanning.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Scan {
+    Opcode,
+    Attachments,
+    // right after the opcode and attachment count, where a namespace
+    // may start.
+    Header,
+    Namespace,
+    Id,
+    // an array or object payload, ending with its closing bracket.
+    Payload,
+    // anything else, ending with the message.
+    Rest,
+}
+
+impl Default for Scan {
+    fn default() -> Scan {
+        Scan::Opcode
+    }
+}
+
+impl PacketDecoder {
+    pub fn new() -> PacketDecoder {
+        PacketDecoder::default()
+    }
+
+    /// Feeds the next chunk of bytes, returning the packets it
+    /// completed. A packet failing to decode doesn't stop the packets
+    /// after it from being decoded; if any failed, the packets that
+    /// did decode are returned along with the errors in a `FeedError`.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Packet>, FeedError> {
+        self.buf.extend_from_slice(chunk);
+        let mut packets = vec![];
+        let mut errors = vec![];
+
+        while self.scanned < self.buf.len() {
+            let b = self.buf[self.scanned];
+            match self.scan {
+                Scan::Opcode => {
+                    self.opcode = b;
+                    self.scan = if b == Opcode::BinaryEvent as u8 || b == Opcode::BinaryAck as u8 {
+                        Scan::Attachments
+                    } else {
+                        Scan::Header
+                    };
+                }
+                Scan::Attachments => {
+                    if b == b'-' {
+                        self.scan = Scan::Header;
+                    }
+                }
+                Scan::Header if b == b'/' => self.scan = Scan::Namespace,
+                Scan::Namespace => {
+                    if b == b',' {
+                        self.scan = Scan::Id;
+                    }
+                }
+                Scan::Header | Scan::Id if b >= b'0' && b <= b'9' => {
+                    if !takes_id(self.opcode) {
+                        // the digit is the opcode of the next packet.
+                        self.take_into(&mut packets, &mut errors);
+                        continue;
+                    }
+                    self.scan = Scan::Id;
+                }
+                Scan::Header | Scan::Id if b == b'[' || b == b'{' => {
+                    self.scan = Scan::Payload;
+                    self.depth = 1;
+                }
+                Scan::Header | Scan::Id => self.scan = Scan::Rest,
+                Scan::Payload if self.in_string => {
+                    match b {
+                        _ if self.escaped => self.escaped = false,
+                        b'\\' => self.escaped = true,
+                        b'"' => self.in_string = false,
+                        _ => {}
+                    }
+                }
+                Scan::Payload => {
+                    match b {
+                        b'"' => self.in_string = true,
+                        b'[' | b'{' => self.depth += 1,
+                        b']' | b'}' => {
+                            self.depth -= 1;
+                            if self.depth == 0 {
+                                self.scanned += 1;
+                                self.take_into(&mut packets, &mut errors);
+                                continue;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Scan::Rest => {}
             }
-            s.push_str(&id.to_string());
+            self.scanned += 1;
         }
+        if errors.is_empty() {
+            Ok(packets)
+        } else {
+            Err(FeedError {
+                packets: packets,
+                errors: errors,
+            })
+        }
+    }
 
-        if let Some(ref data) = self.data {
-            if nsp {
-                s.push(',');
-            }
-            s.push_str(&to_string(data).unwrap());
+    fn take_into(&mut self, packets: &mut Vec<Packet>, errors: &mut Vec<Error>) {
+        match self.take() {
+            Ok(packet) => packets.push(packet),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    // Decodes the bytes scanned so far as a packet, keeping the rest
+    // for the next one.
+    fn take(&mut self) -> Result<Packet, Error> {
+        let rest = self.buf.split_off(self.scanned);
+        let decoder = PacketDecoder { buf: rest, ..PacketDecoder::default() };
+        Packet::from_bytes(&mem::replace(self, decoder).buf)
+    }
+
+    /// Decodes the bytes fed since the last packet as a packet of its
+    /// own, if there are any. Call this at the end of a message.
+    pub fn finish(&mut self) -> Result<Option<Packet>, Error> {
+        let bytes = mem::replace(self, PacketDecoder::new()).buf;
+        if bytes.is_empty() {
+            return Ok(None);
         }
+        Packet::from_bytes(&bytes).map(Some)
+    }
+}
 
-        s
+// Returns whether packets with the opcode `opcode` may carry an id.
+fn takes_id(opcode: u8) -> bool {
+    opcode != Opcode::Connect as u8 && opcode != Opcode::Disconnect as u8 &&
+    opcode != Opcode::Error as u8
+}
+
+// Parses `js` as a single JSON value. Anything but whitespace after
+// the value is reported as trailing data, at its position in `js`
+// plus `offset`.
+fn parse_json(js: &str, offset: usize) -> Result<Value, Error> {
+    let consumed = Cell::new(0);
+    let mut de = Deserializer::new(js.bytes().map(|b| {
+        consumed.set(consumed.get() + 1);
+        Ok(b)
+    }));
+
+    let value = try!(Value::deserialize(&mut de));
+    if de.end().is_err() {
+        // the deserializer stops right after reading the first
+        // trailing byte.
+        return Err(Error::TrailingData(offset + consumed.get() - 1));
     }
+    Ok(value)
 }
 
 #[cfg(test)]
 mod tests {
     use super::Opcode::*;
-    use super::Packet;
+    use super::{Packet, PacketDecoder, JsonFormat};
+    use serde_json::Value;
+    use serde_json::ser::{to_string, to_string_pretty};
     use serde_json::value::to_value;
+    use serde_json::builder::ObjectBuilder;
 
     macro_rules! packet {
         ((data $data:expr ); $(( $x:ident $y:expr ));*) => {
@@ -329,4 +811,290 @@ mod tests {
     test!(attachment, packet!((data &vec![1]);
                               (attachments_num 1);
                               (opcode BinaryEvent)), "51-[1]");
+    #[test]
+    fn binary_without_attachments() {
+        let decoded = Packet::from_bytes(b"50-[\"x\"]").expect("Decoding packet");
+        assert_eq!(decoded.opcode, BinaryEvent);
+        assert_eq!(decoded.attachments_num, 0);
+        assert!(!decoded.has_attachments());
+
+        let decoded = Packet::from_bytes(b"60-4[\"x\"]").expect("Decoding packet");
+        assert_eq!(decoded.opcode, BinaryAck);
+        assert_eq!(decoded.id, Some(4));
+        assert!(!decoded.has_attachments());
+    }
+
+    #[test]
+    fn has_attachments() {
+        let mut decoded = Packet::from_bytes(b"51-[\"x\"]").expect("Decoding packet");
+        assert!(decoded.has_attachments());
+        assert!(decoded.add_attachment(vec![1]));
+        assert!(decoded.has_attachments());
+
+        assert!(!packet!((data &vec![1]); (opcode Event)).has_attachments());
+    }
+
+    #[test]
+    fn attachments_received() {
+        let mut decoded = Packet::from_bytes(b"52-[\"x\"]").expect("Decoding packet");
+        assert_eq!(decoded.attachments_received(), 0);
+        assert!(!decoded.add_attachment(vec![1]));
+        assert_eq!((decoded.attachments_received(), decoded.attachments_num), (1, 2));
+    }
+
+    test!(large_integer, packet!((data &vec![::std::u64::MAX]); (opcode Event)),
+          "2[18446744073709551615]");
+    test!(float_precision, packet!((data &vec![0.1, 1e-7, 123456.789]); (opcode Event)),
+          "2[0.1,1e-7,123456.789]");
+
+    #[test]
+    fn pretty_format() {
+        let packet = packet!((data &vec![1, 2]); (id Some(3)));
+        let encoded = packet.encode_with(JsonFormat::Pretty);
+
+        assert_eq!(encoded, "23[\n  1,\n  2\n]");
+        assert_eq!(Packet::from_bytes(encoded.as_bytes()).expect("Decoding packet").data,
+                   Some(Value::Array(vec![Value::U64(1), Value::U64(2)])));
+    }
+
+    test!(empty_ack, packet!((data &Vec::<usize>::new());
+                             (opcode Ack);
+                             (id Some(1))), "31[]");
+
+    #[test]
+    fn empty_event() {
+        assert!(Packet::from_bytes(b"2[]").is_err());
+    }
+
+    #[test]
+    fn error_display() {
+        use super::Error;
+
+        let json_error = Packet::from_bytes(b"2[").unwrap_err();
+        let utf8_error = Packet::from_bytes(b"0/\xff").unwrap_err();
+
+        assert_eq!(Error::InvalidOpcode(b'9').to_string(), "invalid opcode '9'");
+        assert_eq!(Error::InvalidPacket.to_string(), "invalid packet");
+        assert_eq!(Error::PacketDataNotArray.to_string(), "packet data is not an array");
+        assert!(json_error.to_string().starts_with("invalid JSON: "));
+        assert!(utf8_error.to_string().starts_with("invalid UTF-8: "));
+        assert_eq!(Error::NoEvent.to_string(), "event packet has no event name");
+        assert_eq!(Error::AckIDMissing.to_string(), "ack packet has no id");
+        assert_eq!(Error::NonBinaryHasAttachments.to_string(),
+                   "non-binary packet has attachments");
+        assert_eq!(Error::HandlerPanicked("chat".to_string()).to_string(),
+                   "handler for event \"chat\" panicked");
+        assert_eq!(Error::RateLimited.to_string(), "rate limit exceeded");
+        assert_eq!(Error::MessageTooLarge(2048).to_string(), "message of 2048 bytes is too large");
+    }
+
+    #[test]
+    fn semantic_eq() {
+        let decoded = Packet::from_bytes(b"51-[1]").expect("Decoding packet");
+        let mut buffered = decoded.clone();
+        buffered.add_attachment(vec![1, 2, 3]);
+
+        assert!(decoded != buffered);
+        assert!(decoded.semantic_eq(&buffered));
+        assert!(!decoded.semantic_eq(&packet!((data &vec![1]); (opcode Event))));
+    }
+
+    #[test]
+    fn set_attachments_num() {
+        let mut packet = packet!((data &vec![1]); (opcode Event));
+        packet.set_attachments_num(2);
+        assert_eq!(packet.encode(), "52-[1]");
+        packet.set_attachments_num(0);
+        assert_eq!(packet.encode(), "2[1]");
+    }
+
+    #[test]
+    fn recover_from_invalid_utf8() {
+        let error = Packet::from_bytes(b"2[\"\xff\"]").unwrap_err();
+        assert_eq!(error.code(), "INVALID_UTF8");
+        assert!(Packet::new_error(None, error)
+            .encode()
+            .starts_with("4{\"code\":\"INVALID_UTF8\",\"message\":\"invalid UTF-8: "));
+
+        let decoded = Packet::from_bytes(b"2[\"chat\"]").expect("Decoding packet");
+        assert_eq!(decoded, packet!((data &vec!["chat"]); (opcode Event)));
+    }
+
+    test!(connect_error, Packet::new_connect_error(Some("/abc".to_string()),
+                                                   ObjectBuilder::new()
+                                                   .insert("message", "Not authorized")
+                                                   .build()),
+          "4/abc,{\"message\":\"Not authorized\"}");
+    #[test]
+    fn encode_header_and_data() {
+        let blob: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        let json = to_string(&blob).unwrap();
+        let pretty = to_string_pretty(&blob).unwrap();
+        let packets = vec![packet!((data &blob); (opcode Event)),
+                           packet!((data &blob);
+                                   (namespace Some("/abc".to_string()));
+                                   (id Some(12))),
+                           packet!((data &blob);
+                                   (attachments_num 2);
+                                   (opcode BinaryEvent)),
+                           packet!((namespace Some("/abc".to_string()));
+                                   (opcode Connect))];
+
+        let headers = vec!["2", "2/abc,12", "52-", "0/abc"];
+
+        for (packet, header) in packets.into_iter().zip(headers) {
+            let (json, pretty) = match packet.data {
+                Some(_) => (&json[..], &pretty[..]),
+                None => ("", ""),
+            };
+            assert_eq!(packet.encode(), format!("{}{}", header, json));
+            assert_eq!(packet.encode_with(JsonFormat::Pretty), format!("{}{}", header, pretty));
+            if packet.data.is_some() {
+                assert_eq!(packet.encode_with_json(json), packet.encode());
+            }
+        }
+    }
+
+    test!(large_id, packet!((data &vec![1]);
+                            (id Some(u32::max_value() as u64 + 1))),
+          "24294967296[1]");
+
+    #[test]
+    fn id_overflow() {
+        assert!(Packet::from_bytes(b"318446744073709551615[]").is_ok());
+        assert!(Packet::from_bytes(b"318446744073709551616[]").is_err());
+    }
+
+    #[test]
+    fn lenient_whitespace() {
+        // trailing whitespace after a JSON payload is fine either way
+        for frame in &[&b"2[\"x\"]\r\n"[..], b"\n2[\"x\"]\r\n"] {
+            assert!(Packet::from_bytes_lenient(frame).is_ok());
+        }
+        assert!(Packet::from_bytes(b"2[\"x\"]\r\n").is_ok());
+        assert_eq!(Packet::from_bytes(b"\n2[\"x\"]").unwrap_err().code(), "INVALID_OPCODE");
+
+        assert_eq!(Packet::from_bytes(b"0/chat\r\n").unwrap().namespace,
+                   Some("/chat\r\n".to_string()));
+        assert_eq!(Packet::from_bytes_lenient(b"0/chat\r\n").unwrap().namespace,
+                   Some("/chat".to_string()));
+
+        assert!(Packet::from_bytes(b"0\n").is_err());
+        assert!(Packet::from_bytes(b"4/chat,\n").is_err());
+        assert_eq!(Packet::from_bytes_lenient(b"4/chat,\n").unwrap().namespace,
+                   Some("/chat".to_string()));
+        assert_eq!(Packet::from_bytes_lenient(b"0\n").unwrap().data, None);
+        assert!(Packet::from_bytes_lenient(b" \r\n").is_err());
+    }
+
+    #[test]
+    fn trailing_data() {
+        let decoded = Packet::from_bytes(b"2/abc,[\"x\"] \n").expect("Decoding packet");
+        assert_eq!(decoded.data, Some(to_value(vec!["x"])));
+
+        let error = Packet::from_bytes(b"2/abc,[\"x\"]2[\"y\"]").unwrap_err();
+        assert_eq!(error.code(), "TRAILING_DATA");
+        assert_eq!(error.to_string(), "trailing data at byte 11");
+
+        let error = Packet::from_bytes(b"312[] x").unwrap_err();
+        assert_eq!(error.to_string(), "trailing data at byte 6");
+    }
+
+    test!(connect_auth, Packet::new_connect(Some("/abc".to_string()),
+                                            Some(ObjectBuilder::new()
+                                                 .insert("token", "123")
+                                                 .build())),
+          "0/abc,{\"token\":\"123\"}");
+
+    #[test]
+    fn decode_chunks() {
+        let encoded = b"2/chat,1[\"a\",{\"b\":\"]\\\"}\"}]";
+        for split in 1..encoded.len() {
+            let mut decoder = PacketDecoder::new();
+            assert!(decoder.feed(&encoded[..split]).unwrap().is_empty());
+            let packets = decoder.feed(&encoded[split..]).unwrap();
+            assert_eq!(packets, vec![Packet::from_bytes(encoded).unwrap()]);
+            assert_eq!(decoder.finish().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn decode_several_packets() {
+        let mut decoder = PacketDecoder::new();
+        let packets = decoder.feed(b"2[\"a\"]3/x,4[]0{\"tok").unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].id, Some(4));
+
+        let packets = decoder.feed(b"en\":1}1").unwrap();
+        assert_eq!(packets[0].opcode, Connect);
+        assert_eq!(decoder.finish().unwrap().map(|p| p.opcode), Some(Disconnect));
+    }
+
+    #[test]
+    fn decode_namespace_brackets() {
+        let encoded = b"2/a[b\"{c,1[\"x\"]";
+        let mut decoder = PacketDecoder::new();
+        let packets = decoder.feed(encoded).unwrap();
+        assert_eq!(packets, vec![Packet::from_bytes(encoded).unwrap()]);
+        assert_eq!(packets[0].namespace, Some("/a[b\"{c".to_string()));
+
+        let packets = decoder.feed(b"3/]},2[]").unwrap();
+        assert_eq!(packets[0].namespace, Some("/]}".to_string()));
+        assert_eq!(packets[0].id, Some(2));
+    }
+
+    #[test]
+    fn decode_packets_without_payload() {
+        let mut decoder = PacketDecoder::new();
+        assert!(decoder.feed(b"1").unwrap().is_empty());
+        let packets = decoder.feed(b"2[\"a\"]").unwrap();
+        assert_eq!(packets.iter().map(|p| p.opcode).collect::<Vec<_>>(), vec![Disconnect, Event]);
+        assert_eq!(packets[1].id, None);
+
+        let packets = decoder.feed(b"0/chat,1/chat,2[\"b\"]").unwrap();
+        assert_eq!(packets.iter().map(|p| p.opcode).collect::<Vec<_>>(),
+                   vec![Connect, Disconnect, Event]);
+        assert_eq!(packets[0].namespace, Some("/chat".to_string()));
+        assert_eq!(packets[1].namespace, Some("/chat".to_string()));
+
+        // other packets only end with the message.
+        assert!(decoder.feed(b"4\"no [\"1").unwrap().is_empty());
+        assert_eq!(decoder.finish().unwrap_err().to_string(),
+                   Packet::from_bytes(b"4\"no [\"1").unwrap_err().to_string());
+    }
+
+    #[test]
+    fn decode_invalid_chunk() {
+        let mut decoder = PacketDecoder::new();
+        assert!(decoder.feed(b"9[").unwrap().is_empty());
+        let error = decoder.feed(b"]").unwrap_err();
+        assert!(error.packets.is_empty());
+        assert_eq!(error.errors.iter().map(|e| e.code()).collect::<Vec<_>>(),
+                   vec!["INVALID_OPCODE"]);
+    }
+
+    #[test]
+    fn decode_valid_and_invalid_in_one_chunk() {
+        let mut decoder = PacketDecoder::new();
+        let error = decoder.feed(b"2[\"a\"]9[]2[\"b\"]").unwrap_err();
+        assert_eq!(error.packets,
+                   vec![Packet::from_bytes(b"2[\"a\"]").unwrap(),
+                        Packet::from_bytes(b"2[\"b\"]").unwrap()]);
+        assert_eq!(error.errors.iter().map(|e| e.code()).collect::<Vec<_>>(),
+                   vec!["INVALID_OPCODE"]);
+
+        // decoding carries on with the next chunk as usual
+        assert_eq!(decoder.feed(b"1").unwrap(), vec![]);
+        assert_eq!(decoder.finish().unwrap(), Some(Packet::from_bytes(b"1").unwrap()));
+    }
+
+    test!(connect_no_auth, Packet::new_connect(None, None), "0");
+
+    test!(connect_empty_auth, Packet::new_connect(None, Some(ObjectBuilder::new().build())), "0{}");
+
+    test!(connect_token, Packet::new_connect(None,
+                                             Some(ObjectBuilder::new()
+                                                  .insert("token", "x")
+                                                  .build())),
+          "0{\"token\":\"x\"}");
 }
@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket allowing up to `rate` packets per second, with
+/// bursts of up to `rate` packets.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    /// Takes a token from the bucket, returning `false` if it's empty.
+    pub fn take(&mut self, now: Instant) -> bool {
+        let elapsed = secs(now.duration_since(self.last));
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Refills the bucket.
+    pub fn reset(&mut self, now: Instant) {
+        self.tokens = self.rate;
+        self.last = now;
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::TokenBucket;
+
+    #[test]
+    fn burst_above_limit() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(3, now);
+
+        let taken: Vec<bool> = (0..5).map(|_| bucket.take(now)).collect();
+        assert_eq!(taken, vec![true, true, true, false, false]);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, now);
+
+        assert!(bucket.take(now));
+        assert!(bucket.take(now));
+        assert!(!bucket.take(now));
+
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+
+        // never refills above the limit
+        let much_later = later + Duration::from_secs(10);
+        assert!(bucket.take(much_later));
+        assert!(bucket.take(much_later));
+        assert!(!bucket.take(much_later));
+    }
+
+    #[test]
+    fn reset() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1, now);
+
+        assert!(bucket.take(now));
+        assert!(!bucket.take(now));
+        bucket.reset(now);
+        assert!(bucket.take(now));
+    }
+}
@@ -1,19 +1,141 @@
-use std::sync::{Arc, RwLock};
+use std::cell::RefCell;
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 use std::collections::HashMap;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use data::Data;
-use socket::Socket;
+use data::{Data, SharedEvent};
+use socket::{AckError, Socket, SocketMetrics, DisconnectReason, OnMalformed, SendError};
+use namespace::{Namespace, DEFAULT_NAMESPACE};
+use adapter::{Adapter, BroadcastOperator, BroadcastOptions, MemoryAdapter, RoomEvent};
+use packet::{JsonFormat, ProtocolVersion};
+use handshake::Handshake;
+use session;
+use traffic::TrafficRecorder;
 use engine_io::server;
 use iron::prelude::*;
 use iron::middleware::Handler;
+use iron::status;
 use serde_json::Value;
 
+// The handshake request being handled on this thread. engine.io
+// creates sockets while handling their handshake request, so the
+// connection handler can pick it up from here.
+thread_local!(static HANDSHAKE: RefCell<Option<Handshake>> = RefCell::new(None));
+
+/// The engine.io transports clients are allowed to connect over.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Transports {
+    /// Both long-polling and websockets.
+    All,
+    /// Long-polling only, e.g. behind proxies that break websockets.
+    PollingOnly,
+    /// Websockets only. Clients connect over websockets right away
+    /// instead of upgrading a long-polling connection.
+    WebsocketOnly,
+}
+
+impl Transports {
+    fn allows(&self, transport: Option<&str>) -> bool {
+        match (*self, transport) {
+            (Transports::All, _) => true,
+            (Transports::PollingOnly, Some("polling")) => true,
+            (Transports::WebsocketOnly, Some("websocket")) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Default for Transports {
+    fn default() -> Transports {
+        Transports::All
+    }
+}
+
+/// Totals across all connected clients, returned by
+/// `Server::metrics_snapshot`.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct ServerMetrics {
+    pub connections: usize,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Rooms with at least one client in them, not counting the rooms
+    /// named after client ids.
+    pub rooms: usize,
+    pub pending_acks: usize,
+}
+
+impl ServerMetrics {
+    /// Formats the metrics in the Prometheus text format, to be
+    /// served from a scrape endpoint.
+    pub fn to_prometheus(&self) -> String {
+        let metrics = [
+            ("connections", "gauge", "Connected clients.", self.connections as u64),
+            ("packets_received_total", "counter", "Packets received from clients.", self.packets_in),
+            ("packets_sent_total", "counter", "Packets sent to clients.", self.packets_out),
+            ("bytes_received_total", "counter", "Bytes received from clients.", self.bytes_in),
+            ("bytes_sent_total", "counter", "Bytes sent to clients.", self.bytes_out),
+            ("rooms", "gauge", "Rooms with at least one client.", self.rooms as u64),
+            ("pending_acks", "gauge", "Acks waited for.", self.pending_acks as u64),
+        ];
+
+        let mut out = String::new();
+        for &(name, kind, help, value) in metrics.iter() {
+            out.push_str(&format!("# HELP socketio_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE socketio_{} {}\n", name, kind));
+            out.push_str(&format!("socketio_{} {}\n", name, value));
+        }
+        out
+    }
+
+    // Adds a client's counts. Clients closing while the snapshot is
+    // taken aren't counted.
+    fn add_client(&mut self, counts: SocketMetrics, pending_acks: usize, closed: bool) {
+        if closed {
+            return;
+        }
+        self.connections += 1;
+        self.packets_in += counts.packets_in;
+        self.packets_out += counts.packets_out;
+        self.bytes_in += counts.bytes_in;
+        self.bytes_out += counts.bytes_out;
+        self.pending_acks += pending_acks;
+    }
+}
+
 #[derive(Clone)]
 pub struct Server {
     server: server::Server,
     clients: Arc<RwLock<Vec<Socket>>>,
-    server_rooms: Arc<RwLock<HashMap<String, Vec<Socket>>>>,
+    adapter: Arc<Adapter>,
+    namespaces: Arc<RwLock<HashMap<String, Namespace>>>,
+    json_format: Arc<RwLock<JsonFormat>>,
+    protocol: Arc<RwLock<ProtocolVersion>>,
+    transports: Arc<RwLock<Transports>>,
+    subprotocol: Arc<RwLock<Option<String>>>,
+    on_malformed: Arc<RwLock<OnMalformed>>,
+    lenient: Arc<AtomicBool>,
+    decompress_attachments: Arc<AtomicBool>,
+    strict_events: Arc<AtomicBool>,
+    empty_acks: Arc<AtomicBool>,
+    auto_join: Arc<RwLock<Vec<String>>>,
+    allowed_namespaces: Arc<RwLock<Vec<String>>>,
+    receipts: Arc<AtomicBool>,
+    max_pending_acks: Arc<RwLock<Option<usize>>>,
+    max_message_bytes: Arc<RwLock<Option<usize>>>,
+    session_key: Arc<RwLock<Option<(Vec<u8>, Duration)>>>,
+    id_generator: Arc<RwLock<Option<Box<Fn() -> String>>>>,
     on_connection: Arc<RwLock<Option<Box<Fn(Socket) + 'static>>>>,
+    room_events: Arc<RwLock<Option<Box<Fn(RoomEvent)>>>>,
+    traffic: Arc<RwLock<Option<TrafficRecorder>>>,
+    default_callbacks: Arc<RwLock<HashMap<String, Arc<Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data>>>>>,
+    closed: Arc<AtomicBool>,
 }
 
 unsafe impl Send for Server {}
@@ -21,32 +143,114 @@ unsafe impl Sync for Server {}
 
 impl Server {
     /// Returns a socket.io `Server` instance from an engine.io `Server` instance.
+    #[inline(always)]
     pub fn from_server(server: server::Server) -> Server {
+        Server::with_adapter(server, MemoryAdapter::new())
+    }
+
+    /// Returns a socket.io `Server` instance from an engine.io
+    /// `Server` instance, keeping track of rooms with `adapter`.
+    pub fn with_adapter<A>(server: server::Server, adapter: A) -> Server
+        where A: Adapter + 'static
+    {
+        Server::with_shared_adapter(server, Arc::new(adapter))
+    }
+
+    fn with_shared_adapter(server: server::Server, adapter: Arc<Adapter>) -> Server {
         let socketio_server = Server {
             server: server.clone(),
             clients: Arc::new(RwLock::new(vec![])),
-            server_rooms: Arc::new(RwLock::new(HashMap::new())),
+            adapter: adapter,
+            namespaces: Arc::new(RwLock::new(HashMap::new())),
+            json_format: Arc::new(RwLock::new(JsonFormat::default())),
+            protocol: Arc::new(RwLock::new(ProtocolVersion::default())),
+            transports: Arc::new(RwLock::new(Transports::default())),
+            subprotocol: Arc::new(RwLock::new(None)),
+            on_malformed: Arc::new(RwLock::new(OnMalformed::default())),
+            lenient: Arc::new(AtomicBool::new(false)),
+            decompress_attachments: Arc::new(AtomicBool::new(false)),
+            strict_events: Arc::new(AtomicBool::new(false)),
+            empty_acks: Arc::new(AtomicBool::new(true)),
+            auto_join: Arc::new(RwLock::new(vec![])),
+            allowed_namespaces: Arc::new(RwLock::new(vec![])),
+            receipts: Arc::new(AtomicBool::new(false)),
+            max_pending_acks: Arc::new(RwLock::new(None)),
+            max_message_bytes: Arc::new(RwLock::new(None)),
+            session_key: Arc::new(RwLock::new(None)),
+            id_generator: Arc::new(RwLock::new(None)),
             on_connection: Arc::new(RwLock::new(None)),
+            room_events: Arc::new(RwLock::new(None)),
+            traffic: Arc::new(RwLock::new(None)),
+            default_callbacks: Arc::new(RwLock::new(HashMap::new())),
+            closed: Arc::new(AtomicBool::new(false)),
         };
 
         let cl1 = socketio_server.clone();
 
         server.on_connection(move |so| {
-            let socketio_socket = Socket::new(so.clone(), socketio_server.server_rooms.clone());
+            let socketio_socket = Socket::new(so.clone(),
+                                              socketio_server.adapter.clone(),
+                                              socketio_server.namespaces.clone(),
+                                              socketio_server.room_events.clone(),
+                                              socketio_server.traffic.clone(),
+                                              socketio_server.clients.clone());
+            if let Some(ref generate) = *socketio_server.id_generator.read().unwrap() {
+                socketio_socket.set_id(generate());
+            }
+            socketio_socket.set_json_format(*socketio_server.json_format.read().unwrap());
+            socketio_socket.set_protocol(*socketio_server.protocol.read().unwrap());
+            socketio_socket.set_on_malformed(*socketio_server.on_malformed.read().unwrap());
+            socketio_socket.set_max_pending_acks(*socketio_server.max_pending_acks.read().unwrap());
+            socketio_socket.set_max_message_bytes(*socketio_server.max_message_bytes.read().unwrap());
+            socketio_socket.set_lenient_parsing(socketio_server.lenient.load(Relaxed));
+            socketio_socket.set_decompress_attachments(
+                socketio_server.decompress_attachments.load(Relaxed));
+            socketio_socket.set_strict_events(socketio_server.strict_events.load(Relaxed));
+            socketio_socket.set_empty_acks(socketio_server.empty_acks.load(Relaxed));
+            let allowed_namespaces = socketio_server.allowed_namespaces.read().unwrap().clone();
+            socketio_socket.set_allowed_namespaces(allowed_namespaces);
+            socketio_socket.set_receipts(socketio_server.receipts.load(Relaxed));
+            if let Some(handshake) = HANDSHAKE.with(|h| h.borrow_mut().take()) {
+                let required = socketio_server.subprotocol.read().unwrap().clone();
+                let subprotocol = negotiate_subprotocol(&handshake.subprotocols(),
+                                                        required.as_ref().map(|p| &p[..]));
+                socketio_socket.set_requested_subprotocol(subprotocol.unwrap_or(None));
+                socketio_socket.set_handshake(handshake);
+            }
+            if let Some((ref key, lifetime)) = *socketio_server.session_key.read().unwrap() {
+                socketio_socket.set_session_key(key.clone(), lifetime);
+                // clients with an invalid or expired token just get a
+                // fresh session.
+                let handshake = socketio_socket.handshake();
+                let resumed = handshake.query_param("token")
+                    .and_then(|token| session::verify(key, token, session::now()).ok());
+                if let Some(id) = resumed {
+                    socketio_socket.set_resumed_from(id);
+                }
+            }
 
-            {
-                let mut rooms = socketio_server.server_rooms.write().unwrap();
-                rooms.insert(so.id(), vec![socketio_socket.clone()]);
+            for (event, func) in socketio_server.default_callbacks.read().unwrap().iter() {
+                let func = func.clone();
+                socketio_socket.on(event.clone(), move |params, attachments| func(params, attachments));
             }
+
+            // every client is implicitly in a room named after its
+            // id, so it can be addressed with `Server::to(id)`.
+            socketio_server.adapter.add(&socketio_socket, &socketio_socket.id());
             {
                 let mut clients = socketio_server.clients.write().unwrap();
                 clients.push(socketio_socket.clone());
             }
-            socketio_server.on_connection
-                .read()
-                .unwrap()
-                .as_ref()
-                .map(|func| func(socketio_socket));
+            let namespace = socketio_server.of(DEFAULT_NAMESPACE);
+            namespace.install_callbacks(&socketio_socket);
+            // cloned, so `on_connection` can change the rooms.
+            let auto_join = socketio_server.auto_join.read().unwrap().clone();
+            let on_connection = socketio_server.on_connection.read().unwrap();
+            admit(socketio_socket,
+                  |so| namespace.add_socket(so.clone()),
+                  &auto_join,
+                  |so, room| so.join(room),
+                  on_connection.as_ref().map(|func| &**func));
         });
 
         cl1
@@ -57,6 +261,12 @@ impl Server {
         Server::from_server(server::Server::new())
     }
 
+    /// Returns a `ServerBuilder`, to configure a server in one place.
+    #[inline(always)]
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
     /// Set callback to be called on connecting to a new client.
     #[inline(always)]
     pub fn on_connection<F>(&self, f: F)
@@ -65,28 +275,1335 @@ impl Server {
         *self.on_connection.write().unwrap() = Some(Box::new(f));
     }
 
+    /// Like `on_connection`, but `f` is called on a new thread, so a
+    /// slow callback (e.g. looking the client up in a database)
+    /// doesn't hold up accepting other clients.
+    ///
+    /// Callbacks for different clients may run concurrently, and the
+    /// client may send events before `f` has registered handlers for
+    /// them. Handlers that must see every event should be set with
+    /// `on_event` instead, which installs them before the client's
+    /// first packet is processed.
+    pub fn on_connection_async<F>(&self, f: F)
+        where F: Fn(Socket) + Send + Sync + 'static
+    {
+        self.on_connection(on_new_thread(f));
+    }
+
+    /// Write every packet sent to or received from any client to
+    /// `writer`, one JSON object per line, with the direction, the
+    /// client's id, a timestamp in milliseconds since the Unix epoch
+    /// and the packet's fields. Attachments are recorded by their size
+    /// only. Lines are written on a background thread, so a slow
+    /// writer doesn't hold up clients; records made while it's too far
+    /// behind are dropped, see `traffic_records_dropped`. Replaces any
+    /// writer set before.
+    pub fn record_traffic<W>(&self, writer: W)
+        where W: Write + Send + 'static
+    {
+        *self.traffic.write().unwrap() = Some(TrafficRecorder::new(writer));
+    }
+
+    /// Returns the number of records the current traffic recorder
+    /// dropped because its writer was too far behind, see
+    /// `record_traffic`.
+    pub fn traffic_records_dropped(&self) -> usize {
+        self.traffic.read().unwrap().as_ref().map_or(0, |recorder| recorder.dropped())
+    }
+
+    /// Stop recording traffic, see `record_traffic`.
+    pub fn stop_recording_traffic(&self) {
+        *self.traffic.write().unwrap() = None;
+    }
+
+    /// Set a callback to be called whenever a client joins or leaves
+    /// a room. It's called after the room has been updated, so it
+    /// sees the new membership.
+    pub fn on_room_event<F>(&self, f: F)
+        where F: Fn(RoomEvent) + 'static
+    {
+        *self.room_events.write().unwrap() = Some(Box::new(f));
+    }
+
+    /// Disconnect clients that nothing was sent to or received from
+    /// for longer than `timeout`. Idle clients are looked for on a
+    /// background thread, which stops once the server is closed.
+    pub fn with_idle_timeout(self, timeout: Duration) -> Server {
+        let server = self.clone();
+        let interval = cmp::min(timeout / 2, Duration::from_secs(1));
+
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                if server.closed.load(Relaxed) {
+                    break;
+                }
+                server.evict_idle(timeout);
+            }
+        });
+
+        self
+    }
+
+    fn evict_idle(&self, timeout: Duration) {
+        let idle = {
+            let mut clients = self.clients.write().unwrap();
+            let (idle, active) = split_idle(clients.drain(..).collect(),
+                                            Instant::now(),
+                                            timeout,
+                                            |so| so.last_activity());
+            *clients = active;
+            idle
+        };
+
+        for so in idle {
+            so.disconnect(DisconnectReason::IdleTimeout);
+        }
+    }
+
+    /// Give clients connecting from now on ids generated by
+    /// `generate`, e.g. to encode which node of a cluster a client is
+    /// connected to, instead of the ids of their engine.io connections.
+    /// The ids are used wherever clients are identified, including the
+    /// rooms named after them. Ids must be unique, which is up to
+    /// `generate`.
+    pub fn with_id_generator<F>(self, generate: F) -> Server
+        where F: Fn() -> String + 'static
+    {
+        *self.id_generator.write().unwrap() = Some(Box::new(generate));
+        self
+    }
+
+    /// Speak `version` of the socket.io protocol with clients
+    /// connecting from now on. Defaults to `ProtocolVersion::V4`.
+    pub fn with_protocol(self, version: ProtocolVersion) -> Server {
+        *self.protocol.write().unwrap() = version;
+        self
+    }
+
+    /// Only accept requests over `transports`. Requests over other
+    /// transports, including websocket upgrades of a long-polling
+    /// connection, are answered with `400 Bad Request`. Defaults to
+    /// `Transports::All`.
+    pub fn with_transports(self, transports: Transports) -> Server {
+        *self.transports.write().unwrap() = transports;
+        self
+    }
+
+    /// Only accept WebSocket requests offering the subprotocol
+    /// `subprotocol`, e.g. to require a version of the application's
+    /// protocol. Other WebSocket requests, including upgrades of a
+    /// long-polling connection, are answered with `400 Bad Request`.
+    /// Long-polling requests can't offer a subprotocol, so they're
+    /// accepted; use `Transports::WebsocketOnly` to require it from
+    /// every client.
+    ///
+    /// The subprotocol is only checked, not echoed back in the
+    /// `Sec-WebSocket-Protocol` header of the upgrade response, which
+    /// engine.io writes. Browsers fail connections on which the server
+    /// doesn't echo one of the subprotocols they offered, so this only
+    /// suits clients that don't check, see
+    /// `Socket::requested_subprotocol`.
+    pub fn with_subprotocol(self, subprotocol: &str) -> Server {
+        *self.subprotocol.write().unwrap() = Some(subprotocol.to_string());
+        self
+    }
+
+    /// Set how JSON payloads are serialized for clients connecting
+    /// from now on. Defaults to `JsonFormat::Compact`.
+    pub fn set_json_format(&self, format: JsonFormat) {
+        *self.json_format.write().unwrap() = format;
+    }
+
+    /// Set what to do when clients connecting from now on send a
+    /// malformed packet. Defaults to `OnMalformed::Echo`.
+    pub fn set_on_malformed(&self, policy: OnMalformed) {
+        *self.on_malformed.write().unwrap() = policy;
+    }
+
+    /// Reject events no handler is registered for on clients
+    /// connecting from now on, see `Socket::set_strict_events`.
+    pub fn set_strict_events(&self, strict: bool) {
+        self.strict_events.store(strict, Relaxed);
+    }
+
+    /// Set whether clients connecting from now on get empty acks for
+    /// unhandled events, see `Socket::set_empty_acks`.
+    pub fn set_empty_acks(&self, enabled: bool) {
+        self.empty_acks.store(enabled, Relaxed);
+    }
+
+    /// Set whether clients connecting from now on get a receipt for
+    /// every event they send, see `Socket::set_receipts`.
+    pub fn set_receipts(&self, enabled: bool) {
+        self.receipts.store(enabled, Relaxed);
+    }
+
+    /// Only let clients connecting from now on connect to the
+    /// namespaces in `namespaces`, besides the default one. Connecting
+    /// to any other namespace fails with an "Invalid namespace"
+    /// connect error, without creating the namespace. If
+    /// `namespaces` is empty, clients may connect to any namespace,
+    /// which is the default.
+    pub fn allow_namespaces(&self, namespaces: Vec<String>) {
+        *self.allowed_namespaces.write().unwrap() = namespaces;
+    }
+
+    /// Join each client connecting from now on to `rooms`, before the
+    /// `on_connection` callback is called, e.g. to reach every client
+    /// through an "announcements" room. Replaces the rooms passed
+    /// before.
+    pub fn auto_join(&self, rooms: Vec<String>) {
+        *self.auto_join.write().unwrap() = rooms;
+    }
+
+    /// Sign session tokens handed out with `Socket::session_token` to
+    /// clients connecting from now on with `key`, and accept them for
+    /// `lifetime` after being handed out. Clients reconnecting with a
+    /// valid token can be told apart with `Socket::resumed_from`.
+    /// Tokens can be replayed until they expire, see
+    /// `Socket::session_token`.
+    pub fn set_session_key(&self, key: Vec<u8>, lifetime: Duration) {
+        *self.session_key.write().unwrap() = Some((key, lifetime));
+    }
+
+    /// Limit the number of acks waited for at once on each client
+    /// connecting from now on, see `Socket::set_max_pending_acks`.
+    pub fn set_max_pending_acks(&self, max: Option<usize>) {
+        *self.max_pending_acks.write().unwrap() = max;
+    }
+
+    /// Set whether to ignore whitespace around packets from clients
+    /// connecting from now on, see `Packet::from_bytes_lenient`.
+    pub fn set_lenient_parsing(&self, lenient: bool) {
+        self.lenient.store(lenient, Relaxed);
+    }
+
+    /// Reject messages larger than `max` bytes from each client
+    /// connecting from now on, see `Socket::set_max_message_bytes`.
+    pub fn set_max_message_bytes(&self, max: Option<usize>) {
+        *self.max_message_bytes.write().unwrap() = max;
+    }
+
+    /// Set whether compressed attachments from clients connecting from
+    /// now on are decompressed, see `Socket::set_decompress_attachments`.
+    /// Defaults to `false`.
+    pub fn set_decompress_attachments(&self, enabled: bool) {
+        self.decompress_attachments.store(enabled, Relaxed);
+    }
+
+    /// Set a default callback for `event`, installed on every new
+    /// socket before it processes its first packet. Handlers set
+    /// on a socket with `Socket::on` override the server default.
+    pub fn on_event<F>(&self, event: String, f: F)
+        where F: Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data> + 'static
+    {
+        self.default_callbacks.write().unwrap().insert(event, Arc::new(f));
+    }
+
+    /// Returns the namespace `nsp`, creating it if it doesn't exist.
+    pub fn of(&self, nsp: &str) -> Namespace {
+        let mut namespaces = self.namespaces.write().unwrap();
+        namespaces.entry(nsp.to_string())
+            .or_insert_with(|| Namespace::new(nsp.to_string(), self.adapter.clone()))
+            .clone()
+    }
+
+    /// Returns the names of the namespaces in use: those with a
+    /// client connected, or with middleware or default handlers set.
+    /// Namespaces only obtained with `of`, or left by all of their
+    /// clients, aren't listed.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.namespaces
+            .read()
+            .unwrap()
+            .values()
+            .filter(|namespace| namespace.is_active())
+            .map(|namespace| namespace.name())
+            .collect()
+    }
+
+    /// Removes the namespace `nsp`, disconnecting all of its
+    /// clients. Other namespaces are left untouched.
+    pub fn remove_namespace(&self, nsp: &str) {
+        let namespace = self.namespaces.write().unwrap().remove(nsp);
+        if let Some(namespace) = namespace {
+            namespace.disconnect_all(DisconnectReason::ServerDisconnect);
+        }
+    }
+
+    /// Returns totals across all connected clients. Clients are
+    /// dropped once their connection closes, so counts of packets and
+    /// bytes only cover clients still connected, and the totals drop
+    /// when clients disconnect.
+    pub fn metrics_snapshot(&self) -> ServerMetrics {
+        let mut metrics = ServerMetrics {
+            rooms: self.rooms(false).len(),
+            ..ServerMetrics::default()
+        };
+        let clients = self.clients.read().unwrap();
+        for so in clients.iter() {
+            metrics.add_client(so.metrics(), so.pending_acks().len(), so.is_closed());
+        }
+        metrics
+    }
+
+    /// Returns the names of all rooms with at least one client in
+    /// them. Unless `include_implicit` is set, the rooms every client
+    /// is put in, named after its id, are left out.
+    pub fn rooms(&self, include_implicit: bool) -> Vec<String> {
+        let rooms = self.adapter.rooms();
+        if include_implicit {
+            return rooms;
+        }
+
+        let clients = self.clients.read().unwrap();
+        let ids: Vec<String> = clients.iter().map(|so| so.id()).collect();
+        explicit_rooms(rooms, &ids)
+    }
+
+    /// Returns the ids of the clients in each room, sorted, for
+    /// dashboards and debugging. Rooms of the same name in different
+    /// namespaces are merged. Unless `include_implicit` is set, the
+    /// rooms named after client ids are left out. Only clients of the
+    /// current process are included.
+    pub fn room_map_snapshot(&self, include_implicit: bool) -> HashMap<String, Vec<String>> {
+        let namespaces = self.namespaces();
+        let mut map = HashMap::new();
+        for room in self.rooms(include_implicit) {
+            let mut ids = vec![];
+            for namespace in namespaces.iter() {
+                let opts = BroadcastOptions {
+                    namespace: Some(namespace.clone()),
+                    rooms: vec![room.clone()],
+                    ..BroadcastOptions::default()
+                };
+                ids.extend(self.adapter.clients(&opts).iter().map(|so| so.id()));
+            }
+            ids.sort();
+            map.insert(room, ids);
+        }
+        map
+    }
+
+    /// Returns a `BroadcastOperator` emitting to every client in `room`
+    /// of the default namespace, use `Namespace::to` for the rooms of
+    /// other namespaces. Every client is in a room named after its id,
+    /// so `room` can be a client's id to emit to that client only.
+    pub fn to(&self, room: &str) -> BroadcastOperator {
+        BroadcastOperator::new(self.adapter.clone()).to(room)
+    }
+
+    /// Returns a `BroadcastOperator` emitting to all connections of a
+    /// user, with every connection of the user having joined the room
+    /// `user_room`, e.g. named after the user's id. This is the same
+    /// as `to`, but reads better at call sites.
+    #[inline]
+    pub fn to_user(&self, user_room: &str) -> BroadcastOperator {
+        self.to(user_room)
+    }
+
     /// Close connection to all clients.
     pub fn close(&mut self) {
-        let mut clients = self.clients.write().unwrap();
-        for so in clients.iter_mut() {
+        self.closed.store(true, Relaxed);
+        // closing a client removes it from the list, so it can't be
+        // locked meanwhile.
+        let clients = self.clients.read().unwrap().clone();
+        for mut so in clients {
             so.close();
         }
     }
 
     /// Emits an event with the value `event` and parameters
-    /// `params` to all connected clients.
-    pub fn emit(&self, event: Value, params: Option<Vec<Data>>) {
+    /// `params` to all connected clients, returning the number of
+    /// clients the event was sent to.
+    #[deprecated(note = "use `Server::broadcast`, which does the same")]
+    pub fn emit(&self, event: Value, params: Option<Vec<Data>>) -> usize {
+        self.broadcast(event, params)
+    }
+
+    /// Emits an event with the value `event` and parameters
+    /// `params` to every connected client, in all namespaces,
+    /// returning the number of clients the event was sent to, not
+    /// counting clients whose connection is closed. To emit
+    /// to a single client, use `Socket::emit`.
+    pub fn broadcast(&self, event: Value, params: Option<Vec<Data>>) -> usize {
+        self.emit_where(|_| true, event, params)
+    }
+
+    /// Sends `bytes` verbatim to every connected client, returning the
+    /// number of clients they were sent to. Like `Socket::send_raw`,
+    /// `bytes` isn't checked to be a valid packet.
+    pub fn broadcast_raw(&self, bytes: Vec<u8>) -> usize {
+        let map = self.clients.read().unwrap();
+        map.iter().filter(|so| so.send_raw(bytes.clone()).is_ok()).count()
+    }
+
+    /// Like `broadcast`, but returns the id of every client along
+    /// with whether the event could be sent to it. Clients are
+    /// removed once their connection closes, so only those closing
+    /// while the event is sent get `SendError::Closed`.
+    pub fn emit_results(&self,
+                        event: Value,
+                        params: Option<Vec<Data>>)
+                        -> Vec<(String, Result<(), SendError>)> {
+        let event = SharedEvent::new(event, params);
         let map = self.clients.read().unwrap();
-        for so in map.iter() {
-            so.emit(event.clone(), params.clone());
+        map.iter().map(|so| (so.id(), so.try_emit_shared(&event))).collect()
+    }
+
+    /// Like `broadcast`, but only emits to the clients `predicate`
+    /// returns `true` for, e.g. to target clients by their handshake.
+    /// `predicate` is called with the list of clients locked, so it
+    /// must not connect or close clients.
+    pub fn emit_where<P>(&self, predicate: P, event: Value, params: Option<Vec<Data>>) -> usize
+        where P: Fn(&Socket) -> bool
+    {
+        let event = SharedEvent::new(event, params);
+        emit_matching(&self.clients.read().unwrap(),
+                      predicate,
+                      |so| so.try_emit_shared(&event))
+    }
+
+    /// Emit an event to every connected client, asking each for an
+    /// acknowledgment. `on_each` is called with a client's id and the
+    /// first argument of its ack as soon as it arrives. Clients that
+    /// closed without acknowledging the event are reported with
+    /// `AckError::Closed`: right away if they were closed when it was
+    /// emitted, else at `deadline`. Other clients that didn't
+    /// acknowledge the event by `deadline` are reported with
+    /// `AckError::Timeout`. Acks arriving after `deadline` are ignored.
+    pub fn emit_ack_deadline<F>(&self,
+                                event: Value,
+                                params: Option<Vec<Data>>,
+                                deadline: Instant,
+                                on_each: F)
+        where F: Fn(&str, Result<Value, AckError>) + Send + Sync + 'static
+    {
+        let clients = self.clients.read().unwrap().clone();
+        let ids: Vec<String> = clients.iter().map(|so| so.id()).collect();
+        let state = Arc::new(Mutex::new(AckDeadline::new(clients.len())));
+        let on_each = Arc::new(on_each);
+
+        let event = SharedEvent::new(event, params);
+        let mut acks = vec![];
+        for (i, so) in clients.into_iter().enumerate() {
+            let (s, f, id) = (state.clone(), on_each.clone(), ids[i].clone());
+            let ack_id = so.emit_ack_shared(&event, move |data, _| {
+                let first = match data {
+                    Some(Value::Array(args)) => args.into_iter().next(),
+                    _ => None,
+                };
+                if s.lock().unwrap().respond(i) {
+                    f(&id, Ok(first.unwrap_or(Value::Null)));
+                }
+            });
+            if so.is_closed() && state.lock().unwrap().respond(i) {
+                on_each(&ids[i], Err(AckError::Closed));
+            }
+            acks.push((so, ack_id));
+        }
+
+        thread::spawn(move || {
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            }
+            let expired = state.lock().unwrap().expire(|i| acks[i].0.is_closed());
+            for &(ref so, ack_id) in acks.iter() {
+                so.cancel_ack(ack_id);
+            }
+            for (i, error) in expired {
+                on_each(&ids[i], Err(error));
+            }
+        });
+    }
+
+    /// Emit an event to every connected client, asking each for an
+    /// acknowledgment. `on_complete` is called once, with the id of
+    /// every client and its ack, when all clients acknowledged the
+    /// event or `timeout` elapsed, whichever comes first. Clients that
+    /// didn't acknowledge the event in time get `None`, as do clients
+    /// already closed when the event is emitted, without waiting for
+    /// `timeout`.
+    pub fn emit_ack_all<F>(&self,
+                           event: Value,
+                           params: Option<Vec<Data>>,
+                           timeout: Duration,
+                           on_complete: F)
+        where F: FnOnce(Vec<(String, Option<Value>)>) + Send + 'static
+    {
+        let clients = self.clients.read().unwrap().clone();
+        let ids = clients.iter().map(|so| so.id()).collect();
+        let collector = Arc::new(Mutex::new(AckCollector::new(ids, on_complete)));
+        if clients.is_empty() {
+            collector.lock().unwrap().time_out();
+            return;
+        }
+
+        let event = SharedEvent::new(event, params);
+        let mut acks = vec![];
+        for (i, so) in clients.into_iter().enumerate() {
+            let collector = collector.clone();
+            let c = collector.clone();
+            let id = so.emit_ack_shared(&event, move |data, _| {
+                c.lock().unwrap().respond(i, data);
+            });
+            // a closed client never answers, and may have closed
+            // after its ack was registered.
+            if so.is_closed() {
+                collector.lock().unwrap().respond(i, None);
+            }
+            acks.push((so, id));
+        }
+
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            collector.lock().unwrap().time_out();
+            for (so, id) in acks {
+                so.cancel_ack(id);
+            }
+        });
+    }
+}
+
+/// Returned by `ServerBuilder::build` when an option was given an
+/// invalid value.
+#[derive(PartialEq, Clone, Debug)]
+pub struct ConfigError {
+    /// Name of the `ServerBuilder` method given the value.
+    pub option: &'static str,
+    pub reason: String,
+}
+
+/// Configures a `Server` in one place, obtained with
+/// `Server::builder`. Options that aren't set keep the defaults of
+/// `Server::new`, and each option is documented on the `Server`
+/// method it corresponds to. Values are checked as they're set, and
+/// `build` fails with the first invalid one.
+pub struct ServerBuilder {
+    engine: Option<server::Server>,
+    adapter: Option<Arc<Adapter>>,
+    protocol: ProtocolVersion,
+    transports: Transports,
+    subprotocol: Option<String>,
+    json_format: JsonFormat,
+    on_malformed: OnMalformed,
+    idle_timeout: Option<Duration>,
+    id_generator: Option<Box<Fn() -> String>>,
+    allowed_namespaces: Vec<String>,
+    auto_join: Vec<String>,
+    max_pending_acks: Option<usize>,
+    max_message_bytes: Option<usize>,
+    session_key: Option<(Vec<u8>, Duration)>,
+    strict_events: bool,
+    empty_acks: bool,
+    receipts: bool,
+    lenient_parsing: bool,
+    decompress_attachments: bool,
+    error: Option<ConfigError>,
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            engine: None,
+            adapter: None,
+            protocol: ProtocolVersion::default(),
+            transports: Transports::default(),
+            subprotocol: None,
+            json_format: JsonFormat::default(),
+            on_malformed: OnMalformed::default(),
+            idle_timeout: None,
+            id_generator: None,
+            allowed_namespaces: vec![],
+            auto_join: vec![],
+            max_pending_acks: None,
+            max_message_bytes: None,
+            session_key: None,
+            strict_events: false,
+            empty_acks: true,
+            receipts: false,
+            lenient_parsing: false,
+            decompress_attachments: false,
+            error: None,
+        }
+    }
+
+    /// Use the engine.io server `engine`, see `Server::from_server`.
+    pub fn engine(mut self, engine: server::Server) -> ServerBuilder {
+        self.engine = Some(engine);
+        self
+    }
+
+    /// Keep track of rooms with `adapter`, see `Server::with_adapter`.
+    pub fn adapter<A>(mut self, adapter: A) -> ServerBuilder
+        where A: Adapter + 'static
+    {
+        self.adapter = Some(Arc::new(adapter));
+        self
+    }
+
+    /// See `Server::with_protocol`.
+    pub fn protocol(mut self, version: ProtocolVersion) -> ServerBuilder {
+        self.protocol = version;
+        self
+    }
+
+    /// See `Server::with_transports`.
+    pub fn transports(mut self, transports: Transports) -> ServerBuilder {
+        self.transports = transports;
+        self
+    }
+
+    /// See `Server::with_subprotocol`. Subprotocols are HTTP tokens,
+    /// so they can't be empty, or contain spaces or separators.
+    pub fn subprotocol(mut self, subprotocol: &str) -> ServerBuilder {
+        if !is_token(subprotocol) {
+            return self.invalid("subprotocol", format!("{:?} isn't a valid token", subprotocol));
+        }
+        self.subprotocol = Some(subprotocol.to_string());
+        self
+    }
+
+    /// See `Server::set_json_format`.
+    pub fn json_format(mut self, format: JsonFormat) -> ServerBuilder {
+        self.json_format = format;
+        self
+    }
+
+    /// See `Server::set_on_malformed`.
+    pub fn on_malformed(mut self, policy: OnMalformed) -> ServerBuilder {
+        self.on_malformed = policy;
+        self
+    }
+
+    /// See `Server::with_idle_timeout`. The timeout can't be zero.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        if timeout == Duration::from_secs(0) {
+            return self.invalid("idle_timeout", "must be longer than zero".to_string());
+        }
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Server::with_id_generator`.
+    pub fn id_generator<F>(mut self, generate: F) -> ServerBuilder
+        where F: Fn() -> String + 'static
+    {
+        self.id_generator = Some(Box::new(generate));
+        self
+    }
+
+    /// See `Server::allow_namespaces`. Namespace names must start
+    /// with a `/`.
+    pub fn allow_namespaces(mut self, namespaces: Vec<String>) -> ServerBuilder {
+        if let Some(nsp) = namespaces.iter().find(|nsp| !nsp.starts_with('/')) {
+            let reason = format!("namespace {:?} doesn't start with a '/'", nsp);
+            return self.invalid("allow_namespaces", reason);
         }
+        self.allowed_namespaces = namespaces;
+        self
+    }
+
+    /// See `Server::auto_join`. Room names can't be empty.
+    pub fn auto_join(mut self, rooms: Vec<String>) -> ServerBuilder {
+        if rooms.iter().any(|room| room.is_empty()) {
+            return self.invalid("auto_join", "room names can't be empty".to_string());
+        }
+        self.auto_join = rooms;
+        self
+    }
+
+    /// See `Server::set_max_pending_acks`. The limit can't be zero.
+    pub fn max_pending_acks(mut self, max: usize) -> ServerBuilder {
+        if max == 0 {
+            return self.invalid("max_pending_acks", "must be at least 1".to_string());
+        }
+        self.max_pending_acks = Some(max);
+        self
+    }
+
+    /// See `Server::set_max_message_bytes`. The limit can't be zero.
+    pub fn max_message_bytes(mut self, max: usize) -> ServerBuilder {
+        if max == 0 {
+            return self.invalid("max_message_bytes", "must be at least 1".to_string());
+        }
+        self.max_message_bytes = Some(max);
+        self
+    }
+
+    /// See `Server::set_session_key`. The key can't be empty, and the
+    /// lifetime can't be zero.
+    pub fn session_key(mut self, key: Vec<u8>, lifetime: Duration) -> ServerBuilder {
+        if key.is_empty() {
+            return self.invalid("session_key", "the key can't be empty".to_string());
+        }
+        if lifetime == Duration::from_secs(0) {
+            return self.invalid("session_key", "the lifetime must be longer than zero".to_string());
+        }
+        self.session_key = Some((key, lifetime));
+        self
+    }
+
+    /// See `Server::set_strict_events`.
+    pub fn strict_events(mut self, strict: bool) -> ServerBuilder {
+        self.strict_events = strict;
+        self
+    }
+
+    /// See `Server::set_empty_acks`.
+    pub fn empty_acks(mut self, enabled: bool) -> ServerBuilder {
+        self.empty_acks = enabled;
+        self
+    }
+
+    /// See `Server::set_receipts`.
+    pub fn receipts(mut self, enabled: bool) -> ServerBuilder {
+        self.receipts = enabled;
+        self
+    }
+
+    /// See `Server::set_lenient_parsing`.
+    pub fn lenient_parsing(mut self, lenient: bool) -> ServerBuilder {
+        self.lenient_parsing = lenient;
+        self
+    }
+
+    /// See `Server::set_decompress_attachments`.
+    pub fn decompress_attachments(mut self, enabled: bool) -> ServerBuilder {
+        self.decompress_attachments = enabled;
+        self
+    }
+
+    /// Returns the configured server, or the first invalid value that
+    /// was set.
+    pub fn build(self) -> Result<Server, ConfigError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        let engine = self.engine.unwrap_or_else(server::Server::new);
+        let adapter = self.adapter.unwrap_or_else(|| Arc::new(MemoryAdapter::new()));
+        let mut server = Server::with_shared_adapter(engine, adapter)
+            .with_protocol(self.protocol)
+            .with_transports(self.transports);
+        if let Some(subprotocol) = self.subprotocol {
+            server = server.with_subprotocol(&subprotocol);
+        }
+        *server.id_generator.write().unwrap() = self.id_generator;
+        server.set_json_format(self.json_format);
+        server.set_on_malformed(self.on_malformed);
+        server.allow_namespaces(self.allowed_namespaces);
+        server.auto_join(self.auto_join);
+        server.set_max_pending_acks(self.max_pending_acks);
+        server.set_max_message_bytes(self.max_message_bytes);
+        if let Some((key, lifetime)) = self.session_key {
+            server.set_session_key(key, lifetime);
+        }
+        server.set_strict_events(self.strict_events);
+        server.set_empty_acks(self.empty_acks);
+        server.set_receipts(self.receipts);
+        server.set_lenient_parsing(self.lenient_parsing);
+        server.set_decompress_attachments(self.decompress_attachments);
+        if let Some(timeout) = self.idle_timeout {
+            server = server.with_idle_timeout(timeout);
+        }
+        Ok(server)
+    }
+
+    // Keeps the first invalid value, for `build` to report.
+    fn invalid(mut self, option: &'static str, reason: String) -> ServerBuilder {
+        if self.error.is_none() {
+            self.error = Some(ConfigError {
+                option: option,
+                reason: reason,
+            });
+        }
+        self
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+// Splits `clients` into those idle for longer than `timeout` at `now`,
+// going by the time of their `last_activity`, and the others.
+fn split_idle<T, F>(clients: Vec<T>, now: Instant, timeout: Duration, last_activity: F)
+                    -> (Vec<T>, Vec<T>)
+    where F: Fn(&T) -> Instant
+{
+    clients.into_iter().partition(|so| now.duration_since(last_activity(so)) > timeout)
+}
+
+// Adds a new client to its namespace with `add` and to the
+// `auto_join` rooms with `join` before handing it to `on_connection`,
+// so the callback can already reach it through those rooms.
+fn admit<C, A, J, F>(so: C, add: A, auto_join: &[String], join: J, on_connection: Option<&F>)
+    where A: FnOnce(&C),
+          J: Fn(&C, String),
+          F: Fn(C) + ?Sized
+{
+    add(&so);
+    for room in auto_join {
+        join(&so, room.clone());
+    }
+    if let Some(func) = on_connection {
+        func(so);
+    }
+}
+
+// Emits an event with `emit` to the clients `predicate` returns `true`
+// for, returning the number of clients it was sent to.
+fn emit_matching<C, P, E>(clients: &[C], predicate: P, emit: E) -> usize
+    where P: Fn(&C) -> bool,
+          E: Fn(&C) -> Result<(), SendError>
+{
+    clients.iter().filter(|so| predicate(so)).filter(|so| emit(so).is_ok()).count()
+}
+
+// Wraps `f` so that each call runs it on a new thread, without
+// waiting for it to return.
+fn on_new_thread<T, F>(f: F) -> Box<Fn(T)>
+    where T: Send + 'static,
+          F: Fn(T) + Send + Sync + 'static
+{
+    let f = Arc::new(f);
+    Box::new(move |arg| {
+        let f = f.clone();
+        thread::spawn(move || f(arg));
+    })
+}
+
+// Returns whether `s` is an HTTP token, as subprotocols must be.
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c > ' ' && c < '\x7f' && !"()<>@,;:\\\"/[]?={}".contains(c))
+}
+
+// Keeps track of which clients acknowledged an event emitted with
+// `Server::emit_ack_deadline` before its deadline.
+struct AckDeadline {
+    answered: Vec<bool>,
+    expired: bool,
+}
+
+impl AckDeadline {
+    fn new(clients: usize) -> AckDeadline {
+        AckDeadline {
+            answered: vec![false; clients],
+            expired: false,
+        }
+    }
+
+    // Returns whether the ack of client `i` is to be reported.
+    fn respond(&mut self, i: usize) -> bool {
+        if self.expired || self.answered[i] {
+            return false;
+        }
+        self.answered[i] = true;
+        true
+    }
+
+    // Returns the clients that didn't acknowledge the event in time,
+    // and why, going by whether client `i` is `closed`.
+    fn expire<C: Fn(usize) -> bool>(&mut self, closed: C) -> Vec<(usize, AckError)> {
+        if self.expired {
+            return vec![];
+        }
+        self.expired = true;
+        (0..self.answered.len())
+            .filter(|&i| !self.answered[i])
+            .map(|i| (i, if closed(i) { AckError::Closed } else { AckError::Timeout }))
+            .collect()
+    }
+}
+
+// Collects the acks of an event emitted with `Server::emit_ack_all`.
+struct AckCollector<F> {
+    responses: Vec<(String, Option<Value>)>,
+    answered: Vec<bool>,
+    remaining: usize,
+    on_complete: Option<F>,
+}
+
+impl<F> AckCollector<F>
+    where F: FnOnce(Vec<(String, Option<Value>)>)
+{
+    fn new(ids: Vec<String>, on_complete: F) -> AckCollector<F> {
+        AckCollector {
+            answered: vec![false; ids.len()],
+            remaining: ids.len(),
+            responses: ids.into_iter().map(|id| (id, None)).collect(),
+            on_complete: Some(on_complete),
+        }
+    }
+
+    fn respond(&mut self, i: usize, data: Option<Value>) {
+        if self.answered[i] || self.on_complete.is_none() {
+            return;
+        }
+        self.answered[i] = true;
+        self.responses[i].1 = data;
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.time_out();
+        }
+    }
+
+    fn time_out(&mut self) {
+        if let Some(f) = self.on_complete.take() {
+            f(self.responses.drain(..).collect());
+        }
+    }
+}
+
+// Returns the subprotocol of a connection on which the client offered
+// `offered`, or `Err` if it didn't offer the `required` one.
+fn negotiate_subprotocol(offered: &[String], required: Option<&str>) -> Result<Option<String>, ()> {
+    match required {
+        Some(required) if offered.iter().any(|p| p == required) => Ok(Some(required.to_string())),
+        Some(_) => Err(()),
+        None => Ok(offered.first().cloned()),
     }
 }
 
+fn explicit_rooms(rooms: Vec<String>, ids: &[String]) -> Vec<String> {
+    rooms.into_iter().filter(|room| !ids.contains(room)).collect()
+}
 
 impl Handler for Server {
-    #[inline(always)]
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        self.server.handle(req)
+        let query = req.url.clone().into_generic_url().query().map(|q| q.to_string());
+        let transport = Handshake::transport(query.as_ref().map(|q| &q[..]));
+        if !self.transports.read().unwrap().allows(transport) {
+            return Ok(Response::with((status::BadRequest,
+                                      "{\"code\":0,\"message\":\"Transport unknown\"}")));
+        }
+
+        if transport == Some("websocket") {
+            let handshake = Handshake::from_request(req);
+            let required = self.subprotocol.read().unwrap().clone();
+            let subprotocol = match negotiate_subprotocol(&handshake.subprotocols(),
+                                                          required.as_ref().map(|p| &p[..])) {
+                Ok(subprotocol) => subprotocol,
+                Err(()) => {
+                    return Ok(Response::with((status::BadRequest,
+                                              "{\"code\":3,\"message\":\"Bad request\"}")));
+                }
+            };
+            // upgrades of a long-polling connection.
+            if let Some(sid) = handshake.query_param("sid") {
+                let clients = self.clients.read().unwrap();
+                if let Some(so) = clients.iter().find(|so| so.engine_id() == sid) {
+                    so.set_requested_subprotocol(subprotocol);
+                }
+            }
+        }
+
+        if Handshake::has_session_id(query.as_ref().map(|q| &q[..])) {
+            return self.server.handle(req);
+        }
+
+        HANDSHAKE.with(|h| *h.borrow_mut() = Some(Handshake::from_request(req)));
+        let res = self.server.handle(req);
+        HANDSHAKE.with(|h| *h.borrow_mut() = None);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashMap};
+    use std::rc::Rc;
+    use std::sync::{mpsc, Arc, Barrier, Mutex};
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::time::{Duration, Instant};
+
+    use super::{admit, emit_matching, explicit_rooms, negotiate_subprotocol, on_new_thread,
+                split_idle, AckCollector, AckDeadline, ConfigError, Server, ServerMetrics,
+                Transports};
+    use packet::{JsonFormat, ProtocolVersion};
+    use adapter::{add_to, room_key, Member};
+    use handshake::Handshake;
+    use socket::{AckError, OnMalformed, SendError, SocketMetrics};
+    use serde_json::Value;
+
+    #[test]
+    fn connection_callbacks_on_new_threads() {
+        // each callback blocks until both have started, so they only
+        // return if neither waits for the other.
+        let started = Arc::new(Barrier::new(2));
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let on_connection = on_new_thread(move |id: u32| {
+            started.wait();
+            tx.lock().unwrap().send(id).unwrap();
+        });
+        on_connection(1);
+        on_connection(2);
+
+        let timeout = Duration::from_secs(5);
+        let mut ids = vec![rx.recv_timeout(timeout).unwrap(), rx.recv_timeout(timeout).unwrap()];
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn builder() {
+        let mut server = Server::builder()
+            .protocol(ProtocolVersion::V5)
+            .transports(Transports::WebsocketOnly)
+            .subprotocol("chat.v1")
+            .json_format(JsonFormat::Pretty)
+            .on_malformed(OnMalformed::Disconnect)
+            .idle_timeout(Duration::from_secs(60))
+            .id_generator(|| "node-1".to_string())
+            .allow_namespaces(vec!["/chat".to_string()])
+            .auto_join(vec!["announcements".to_string()])
+            .max_pending_acks(8)
+            .max_message_bytes(1 << 20)
+            .session_key(b"secret".to_vec(), Duration::from_secs(300))
+            .strict_events(true)
+            .empty_acks(false)
+            .receipts(true)
+            .lenient_parsing(true)
+            .decompress_attachments(true)
+            .build()
+            .expect("Building server");
+
+        assert_eq!(*server.protocol.read().unwrap(), ProtocolVersion::V5);
+        assert_eq!(*server.transports.read().unwrap(), Transports::WebsocketOnly);
+        assert_eq!(*server.subprotocol.read().unwrap(), Some("chat.v1".to_string()));
+        assert_eq!(*server.json_format.read().unwrap(), JsonFormat::Pretty);
+        assert_eq!(*server.on_malformed.read().unwrap(), OnMalformed::Disconnect);
+        assert_eq!(server.id_generator.read().unwrap().as_ref().map(|f| f()),
+                   Some("node-1".to_string()));
+        assert_eq!(*server.allowed_namespaces.read().unwrap(), vec!["/chat".to_string()]);
+        assert_eq!(*server.auto_join.read().unwrap(), vec!["announcements".to_string()]);
+        assert_eq!(*server.max_pending_acks.read().unwrap(), Some(8));
+        assert_eq!(*server.max_message_bytes.read().unwrap(), Some(1 << 20));
+        assert_eq!(*server.session_key.read().unwrap(),
+                   Some((b"secret".to_vec(), Duration::from_secs(300))));
+        assert!(server.strict_events.load(Relaxed));
+        assert!(!server.empty_acks.load(Relaxed));
+        assert!(server.receipts.load(Relaxed));
+        assert!(server.lenient.load(Relaxed));
+        assert!(server.decompress_attachments.load(Relaxed));
+        // stops the idle timeout thread.
+        server.close();
+    }
+
+    #[test]
+    fn builder_validation() {
+        let error = |builder: super::ServerBuilder| builder.build().err().map(|e| e.option);
+        assert_eq!(error(Server::builder().subprotocol("chat v1")), Some("subprotocol"));
+        assert_eq!(error(Server::builder().subprotocol("")), Some("subprotocol"));
+        assert_eq!(error(Server::builder().idle_timeout(Duration::from_secs(0))),
+                   Some("idle_timeout"));
+        assert_eq!(error(Server::builder().allow_namespaces(vec!["chat".to_string()])),
+                   Some("allow_namespaces"));
+        assert_eq!(error(Server::builder().auto_join(vec!["".to_string()])), Some("auto_join"));
+        assert_eq!(error(Server::builder().max_pending_acks(0)), Some("max_pending_acks"));
+        assert_eq!(error(Server::builder().max_message_bytes(0)), Some("max_message_bytes"));
+        assert_eq!(error(Server::builder().session_key(vec![], Duration::from_secs(1))),
+                   Some("session_key"));
+
+        // the first invalid value is reported.
+        let result = Server::builder()
+            .max_pending_acks(0)
+            .protocol(ProtocolVersion::V5)
+            .max_message_bytes(0)
+            .build();
+        assert_eq!(result.err(),
+                   Some(ConfigError {
+                       option: "max_pending_acks",
+                       reason: "must be at least 1".to_string(),
+                   }));
+    }
+
+    #[test]
+    fn subprotocol_negotiation() {
+        let offered = vec!["chat.v2".to_string(), "chat.v1".to_string()];
+        assert_eq!(negotiate_subprotocol(&offered, None), Ok(Some("chat.v2".to_string())));
+        assert_eq!(negotiate_subprotocol(&offered, Some("chat.v1")),
+                   Ok(Some("chat.v1".to_string())));
+        assert_eq!(negotiate_subprotocol(&offered, Some("chat.v3")), Err(()));
+        assert_eq!(negotiate_subprotocol(&[], Some("chat.v1")), Err(()));
+        assert_eq!(negotiate_subprotocol(&[], None), Ok(None));
+    }
+
+    #[test]
+    fn explicit_rooms_skip_ids() {
+        let rooms = vec!["a".to_string(), "lobby".to_string(), "b".to_string()];
+        let ids = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(explicit_rooms(rooms, &ids), vec!["lobby".to_string()]);
+    }
+
+    #[test]
+    fn metrics_skip_closed_clients() {
+        let counts = SocketMetrics {
+            packets_in: 2,
+            packets_out: 3,
+            bytes_in: 20,
+            bytes_out: 30,
+        };
+        let mut metrics = ServerMetrics::default();
+        metrics.add_client(counts, 1, false);
+        metrics.add_client(counts, 4, true);
+        metrics.add_client(counts, 0, false);
+
+        assert_eq!(metrics,
+                   ServerMetrics {
+                       connections: 2,
+                       packets_in: 4,
+                       packets_out: 6,
+                       bytes_in: 40,
+                       bytes_out: 60,
+                       rooms: 0,
+                       pending_acks: 1,
+                   });
+    }
+
+    #[test]
+    fn prometheus_format() {
+        let metrics = ServerMetrics {
+            connections: 2,
+            packets_in: 5,
+            bytes_out: 120,
+            ..ServerMetrics::default()
+        };
+        let text = metrics.to_prometheus();
+        assert!(text.starts_with("# HELP socketio_connections Connected clients.\n\
+                                  # TYPE socketio_connections gauge\n\
+                                  socketio_connections 2\n"));
+        assert!(text.contains("\n# TYPE socketio_packets_received_total counter\n\
+                               socketio_packets_received_total 5\n"));
+        assert!(text.contains("\nsocketio_bytes_sent_total 120\n"));
+        assert!(text.ends_with("\nsocketio_pending_acks 0\n"));
+        assert_eq!(text.lines().count(), 21);
+    }
+
+    #[test]
+    fn allowed_transports() {
+        assert!(Transports::All.allows(Some("polling")));
+        assert!(Transports::All.allows(None));
+        assert!(Transports::PollingOnly.allows(Some("polling")));
+        assert!(!Transports::PollingOnly.allows(Some("websocket")));
+        assert!(Transports::WebsocketOnly.allows(Some("websocket")));
+        assert!(!Transports::WebsocketOnly.allows(Some("polling")));
+        assert!(!Transports::WebsocketOnly.allows(None));
+    }
+
+    #[test]
+    fn idle_clients() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(100);
+        let clients = vec![("a", start),
+                           ("b", start + Duration::from_secs(95)),
+                           ("c", start + Duration::from_secs(90)),
+                           ("d", start + Duration::from_secs(89))];
+
+        let (idle, active) = split_idle(clients, now, Duration::from_secs(10), |c| c.1);
+        assert_eq!(idle.iter().map(|c| c.0).collect::<Vec<_>>(), vec!["a", "d"]);
+        assert_eq!(active.iter().map(|c| c.0).collect::<Vec<_>>(), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn emit_where_handshake() {
+        struct Client {
+            id: &'static str,
+            handshake: Handshake,
+            closed: bool,
+        }
+
+        let client = |id, tenant: Option<&str>, admin, closed| {
+            let mut handshake = Handshake::default();
+            if let Some(tenant) = tenant {
+                handshake.headers.insert("x-tenant".to_string(), tenant.to_string());
+            }
+            let mut auth = BTreeMap::new();
+            auth.insert("admin".to_string(), Value::Bool(admin));
+            handshake.auth = Some(Value::Object(auth));
+            Client {
+                id: id,
+                handshake: handshake,
+                closed: closed,
+            }
+        };
+        let clients = vec![client("a", Some("acme"), false, false),
+                           client("b", Some("other"), true, false),
+                           client("c", None, true, false),
+                           client("d", Some("acme"), true, false),
+                           client("e", Some("acme"), true, true)];
+
+        let sent = RefCell::new(vec![]);
+        let emit = |c: &Client| if c.closed {
+            Err(SendError::Closed)
+        } else {
+            sent.borrow_mut().push(c.id);
+            Ok(())
+        };
+
+        let acme = |c: &Client| c.handshake.header("X-Tenant") == Some("acme");
+        assert_eq!(emit_matching(&clients, acme, &emit), 2);
+        assert_eq!(*sent.borrow(), vec!["a", "d"]);
+
+        sent.borrow_mut().clear();
+        let admin = |c: &Client| {
+            let auth = c.handshake.auth.as_ref();
+            auth.and_then(|auth| auth.find("admin")) == Some(&Value::Bool(true))
+        };
+        assert_eq!(emit_matching(&clients, admin, &emit), 3);
+        assert_eq!(*sent.borrow(), vec!["b", "c", "d"]);
+
+        sent.borrow_mut().clear();
+        assert_eq!(emit_matching(&clients, |_| false, &emit), 0);
+        assert!(sent.borrow().is_empty());
+    }
+
+    #[test]
+    fn auto_join_rooms() {
+        let server = Server::new();
+        assert!(server.auto_join.read().unwrap().is_empty());
+
+        server.auto_join(vec!["announcements".to_string(), "lobby".to_string()]);
+        assert_eq!(*server.auto_join.read().unwrap(),
+                   vec!["announcements".to_string(), "lobby".to_string()]);
+        // later calls replace the rooms rather than adding to them
+        server.auto_join(vec!["news".to_string()]);
+        assert_eq!(*server.auto_join.read().unwrap(), vec!["news".to_string()]);
+        server.auto_join(vec![]);
+        assert!(server.auto_join.read().unwrap().is_empty());
+    }
+
+    #[derive(Clone)]
+    struct Client(&'static str);
+
+    impl Member for Client {
+        fn id(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn namespace(&self) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn admit_joins_auto_join_rooms_first() {
+        let auto_join = vec!["announcements".to_string(), "lobby".to_string()];
+        let namespace = RefCell::new(vec![]);
+        let rooms = RefCell::new(HashMap::new());
+        let members = |room| {
+            let rooms = rooms.borrow();
+            let clients: Vec<Client> = rooms.get(&room_key(None, room)).cloned().unwrap_or(vec![]);
+            clients.iter().map(|c| c.0).collect::<Vec<_>>()
+        };
+        let connected = RefCell::new(vec![]);
+        let on_connection = |so: Client| {
+            // the callback runs with the client already in its rooms
+            assert!(namespace.borrow().contains(&so.0));
+            assert!(members("announcements").contains(&so.0));
+            assert!(members("lobby").contains(&so.0));
+            connected.borrow_mut().push(so.0);
+        };
+        let add = |so: &Client| namespace.borrow_mut().push(so.0);
+        let join = |so: &Client, room: String| add_to(&mut rooms.borrow_mut(), so, &room);
+
+        admit(Client("a"), &add, &auto_join, &join, Some(&on_connection));
+        admit(Client("b"), &add, &auto_join, &join, Some(&on_connection));
+        assert_eq!(*connected.borrow(), vec!["a", "b"]);
+        assert_eq!(members("announcements"), vec!["a", "b"]);
+        assert_eq!(members("lobby"), vec!["a", "b"]);
+
+        // without auto_join rooms or a callback, the client is only added
+        // to its namespace.
+        admit(Client("c"), &add, &[], &join, None::<&Fn(Client)>);
+        assert_eq!(*namespace.borrow(), vec!["a", "b", "c"]);
+        assert_eq!(rooms.borrow().len(), 2);
+        assert_eq!(connected.borrow().len(), 2);
+    }
+
+    #[test]
+    fn namespaces_in_use() {
+        let server = Server::new();
+        server.of("/idle");
+        server.of("/admin").use_middleware(|_| Ok(()));
+        assert_eq!(server.namespaces(), vec!["/admin".to_string()]);
+    }
+
+    #[test]
+    fn ack_deadline() {
+        let mut state = AckDeadline::new(3);
+        assert!(state.respond(1));
+        assert!(!state.respond(1));
+        assert!(state.respond(0));
+        assert_eq!(state.expire(|_| false), vec![(2, AckError::Timeout)]);
+        assert!(!state.respond(2));
+        assert_eq!(state.expire(|_| false), vec![]);
+    }
+
+    #[test]
+    fn ack_deadline_closed_clients() {
+        let mut state = AckDeadline::new(4);
+        assert!(state.respond(0));
+        // client 3 was closed when the event was emitted, and has been
+        // reported already.
+        assert!(state.respond(3));
+        assert_eq!(state.expire(|i| i == 2 || i == 3),
+                   vec![(1, AckError::Timeout), (2, AckError::Closed)]);
+    }
+
+    #[test]
+    fn collect_acks() {
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        let ids = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let mut collector = AckCollector::new(ids, move |acks| *r.borrow_mut() = Some(acks));
+
+        collector.respond(2, Some(Value::U64(3)));
+        collector.respond(0, Some(Value::U64(1)));
+        assert!(result.borrow().is_none());
+        collector.time_out();
+        collector.respond(1, Some(Value::U64(2)));
+
+        assert_eq!(*result.borrow(),
+                   Some(vec![("a".to_string(), Some(Value::U64(1))),
+                             ("b".to_string(), None),
+                             ("c".to_string(), Some(Value::U64(3)))]));
+    }
+
+    #[test]
+    fn collect_all_acks() {
+        let calls = Rc::new(RefCell::new(0));
+        let c = calls.clone();
+        let mut collector = AckCollector::new(vec!["a".to_string()], move |acks| {
+            assert_eq!(acks, vec![("a".to_string(), None)]);
+            *c.borrow_mut() += 1;
+        });
+
+        collector.respond(0, None);
+        collector.time_out();
+        assert_eq!(*calls.borrow(), 1);
+    }
+
+    #[test]
+    fn collect_acks_closed_client() {
+        let result = Rc::new(RefCell::new(None));
+        let r = result.clone();
+        let ids = vec!["a".to_string(), "b".to_string()];
+        let mut collector = AckCollector::new(ids, move |acks| *r.borrow_mut() = Some(acks));
+
+        // "b" is closed, and counted as failed right away.
+        collector.respond(1, None);
+        collector.respond(0, Some(Value::U64(1)));
+        // a late ack from the closed client is ignored.
+        collector.respond(1, Some(Value::U64(2)));
+        assert_eq!(*result.borrow(),
+                   Some(vec![("a".to_string(), Some(Value::U64(1))), ("b".to_string(), None)]));
     }
 }
@@ -0,0 +1,126 @@
+// Session tokens handed to clients by `Socket::session_token`, so they
+// can show which connection they're resuming when reconnecting.
+//
+// A token is `<socket id>.<expiry>.<signature>`, the expiry being in
+// seconds since the Unix epoch, and the signature the hex-encoded
+// HMAC-SHA256 of `<socket id>.<expiry>` with the server's session key.
+//
+// Tokens are bearer tokens: they aren't tied to the client they were
+// handed to, and can be replayed by anyone holding one until it
+// expires.
+
+use std::time::UNIX_EPOCH;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Why a session token was rejected.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum TokenError {
+    Malformed,
+    Expired,
+    BadSignature,
+}
+
+/// Returns the current time, in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    UNIX_EPOCH.elapsed().map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn issue(key: &[u8], socket_id: &str, expires_at: u64) -> String {
+    let payload = format!("{}.{}", socket_id, expires_at);
+    let signature = to_hex(&mac(key, payload.as_bytes()).finalize().into_bytes());
+    format!("{}.{}", payload, signature)
+}
+
+/// Checks `token` at `now`, returning the id of the socket it was
+/// issued to.
+pub fn verify(key: &[u8], token: &str, now: u64) -> Result<String, TokenError> {
+    // socket ids may contain dots, the expiry and signature can't.
+    let mut parts = token.rsplitn(3, '.');
+    let (hex, expiry, socket_id) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(s), Some(e), Some(id)) => (s, e, id),
+        _ => return Err(TokenError::Malformed),
+    };
+    let expires_at: u64 = try!(expiry.parse().map_err(|_| TokenError::Malformed));
+    let signature = try!(from_hex(hex).ok_or(TokenError::BadSignature));
+
+    // verify_slice compares in constant time.
+    let payload = &token[..token.len() - hex.len() - 1];
+    try!(mac(key, payload.as_bytes())
+        .verify_slice(&signature)
+        .map_err(|_| TokenError::BadSignature));
+    if now >= expires_at {
+        return Err(TokenError::Expired);
+    }
+    Ok(socket_id.to_string())
+}
+
+fn mac(key: &[u8], message: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_hex, issue, mac, to_hex, verify, TokenError};
+    use hmac::Mac;
+
+    #[test]
+    fn hmac_rfc4231() {
+        assert_eq!(to_hex(&mac(&[0x0b; 20], b"Hi There").finalize().into_bytes()),
+                   "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(to_hex(&mac(&[0xaa; 131],
+                               b"Test Using Larger Than Block-Size Key - Hash Key First")
+                              .finalize()
+                              .into_bytes()),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(from_hex(&to_hex(&[0, 9, 0xab, 0xff])), Some(vec![0, 9, 0xab, 0xff]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("\u{e9}"), None);
+    }
+
+    #[test]
+    fn valid_token() {
+        let token = issue(b"secret", "a.b", 100);
+        assert_eq!(verify(b"secret", &token, 99), Ok("a.b".to_string()));
+    }
+
+    #[test]
+    fn expired_token() {
+        let token = issue(b"secret", "abc", 100);
+        assert_eq!(verify(b"secret", &token, 100), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn tampered_token() {
+        let token = issue(b"secret", "abc", 100);
+        assert_eq!(verify(b"secret", &token.replace("abc.100", "abd.100"), 0),
+                   Err(TokenError::BadSignature));
+        assert_eq!(verify(b"secret", &token.replace("abc.100", "abc.999"), 0),
+                   Err(TokenError::BadSignature));
+        assert_eq!(verify(b"other", &token, 0), Err(TokenError::BadSignature));
+        assert_eq!(verify(b"secret", "abc", 0), Err(TokenError::Malformed));
+        assert_eq!(verify(b"secret", "abc.soon.00", 0), Err(TokenError::Malformed));
+    }
+}
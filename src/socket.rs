@@ -1,24 +1,311 @@
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
-use std::sync::atomic::AtomicUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::{Arc, RwLock, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use engine_io::socket;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use data::{encode_data, Data};
-use packet::{Packet, Opcode};
+use serde_json::value::{from_value, Serializer};
+use serde_json::error::Error as JSONError;
+use serde_json::builder::ObjectBuilder;
+use data::{decode_attachments, encode_data, encode_event, is_placeholder, Data, SharedEvent};
+use packet::{Packet, Opcode, Error, JsonFormat, ProtocolVersion};
+use namespace::{Namespace, DEFAULT_NAMESPACE};
+use adapter::{Adapter, BroadcastOperator, RoomEvent};
+use rate_limit::TokenBucket;
+use handshake::Handshake;
+use session;
+use traffic::{Direction, TrafficRecorder};
+
+/// Why the server disconnected a client.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DisconnectReason {
+    /// `Socket::disconnect` was called by the application.
+    ServerDisconnect,
+    /// Nothing was sent to or received from the client for longer
+    /// than the server's idle timeout.
+    IdleTimeout,
+    /// The client sent a malformed packet, and the socket's
+    /// `OnMalformed` policy is `Disconnect`.
+    MalformedPacket,
+    /// The client sent a message larger than the socket's limit, see
+    /// `Socket::set_max_message_bytes`, and the socket's
+    /// `OnMalformed` policy is `Disconnect`.
+    MessageTooLarge,
+}
+
+impl DisconnectReason {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            DisconnectReason::ServerDisconnect => "server disconnect",
+            DisconnectReason::IdleTimeout => "idle timeout",
+            DisconnectReason::MalformedPacket => "malformed packet",
+            DisconnectReason::MessageTooLarge => "message too large",
+        }
+    }
+}
+
+/// Which side ended a connection.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Initiator {
+    /// The client disconnected.
+    Client,
+    /// The server closed the connection, e.g. with `Socket::close` or
+    /// `Socket::disconnect`.
+    Server,
+    /// The underlying transport failed, e.g. the client stopped
+    /// answering pings.
+    Transport,
+}
+
+/// Why the connection to a client was closed, passed to the handler
+/// set with `Socket::on_close`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct CloseReason {
+    pub initiator: Initiator,
+    pub reason: String,
+}
+
+/// A failure of the connection's underlying transport, passed to the
+/// handler set with `Socket::on_transport_error`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct TransportError {
+    /// engine.io's description of the failure, e.g. "ping timeout".
+    pub reason: String,
+}
+
+/// Errors returned when sending to a client fails.
+#[derive(Debug)]
+pub enum SendError {
+    /// The connection to the client has been closed.
+    Closed,
+    /// The event's arguments couldn't be serialized to JSON.
+    Encode(JSONError),
+}
+
+/// Errors returned by `Socket::call`.
+#[derive(Debug)]
+pub enum CallError {
+    /// The request couldn't be serialized to JSON.
+    Encode(JSONError),
+    /// The client didn't acknowledge the request, e.g. because it
+    /// timed out or the connection closed.
+    Ack(AckError),
+    /// The client's response couldn't be deserialized.
+    Decode(JSONError),
+}
+
+/// An error returned by an event handler registered with
+/// `Socket::on_result`, reported to the client through the event's
+/// ack, or returned by `Socket::request` when no ack was received.
+#[derive(PartialEq, Clone, Debug)]
+pub enum AckError {
+    /// The handler rejected the event, with a payload describing why.
+    Rejected(Value),
+    /// The client didn't acknowledge the event in time.
+    Timeout,
+    /// The connection to the client was closed before it
+    /// acknowledged the event.
+    Closed,
+    /// The ack was dropped to make room for newer ones, see
+    /// `Socket::set_max_pending_acks`.
+    Dropped,
+}
+
+impl AckError {
+    fn into_payload(self) -> Value {
+        match self {
+            AckError::Rejected(payload) => payload,
+            AckError::Timeout => Value::String("timeout".to_string()),
+            AckError::Closed => Value::String("closed".to_string()),
+            AckError::Dropped => Value::String("dropped".to_string()),
+        }
+    }
+}
+
+/// The acknowledgment of an event sent with `Socket::request`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct AckResponse {
+    pub data: Option<Value>,
+    pub attachments: Option<Vec<Vec<u8>>>,
+}
+
+/// A file uploaded with an event, passed to handlers registered with
+/// `Socket::on_upload`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Upload {
+    /// The event's arguments, without the one standing in for the
+    /// file.
+    pub args: Vec<Value>,
+    pub data: Vec<u8>,
+}
+
+/// Returned by `Socket::next_event` when the event wasn't received
+/// in time, or the connection closed before it was, and passed to the
+/// callback of `Server::emit_ack_deadline` for clients that didn't
+/// acknowledge the event in time.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct TimeoutError;
+
+/// Returned by `Socket::to_room_broadcast` when the socket hasn't
+/// joined the room.
+#[derive(PartialEq, Clone, Debug)]
+pub struct NotInRoomError {
+    pub room: String,
+}
+
+/// A snapshot of a socket's state, returned by `Socket::debug_dump`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct SocketDebug {
+    pub id: String,
+    pub namespace: Option<String>,
+    /// Names of the events handlers are registered for, sorted.
+    pub events: Vec<String>,
+    /// Ids of the events sent to the client that haven't been
+    /// acknowledged yet, sorted.
+    pub pending_acks: Vec<u64>,
+    /// Rooms joined with `Socket::join`.
+    pub rooms: Vec<String>,
+}
+
+/// Name of the events sent as receipts, see `Socket::set_receipts`.
+pub const RECEIPT_EVENT: &'static str = "$receipt";
+
+// How large a decompressed attachment may grow when the socket has no
+// message limit, see `Socket::set_decompress_attachments`.
+const MAX_DECOMPRESSED_BYTES: usize = 16 << 20;
+
+/// How long the handler for an event took to run, returned by
+/// `Socket::handler_stats`.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct HandlerStat {
+    /// How many times the handler was called.
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl HandlerStat {
+    fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        if elapsed > self.max {
+            self.max = elapsed;
+        }
+    }
+}
+
+/// Counts of what was sent to and received from a client, returned
+/// by `Socket::metrics`. Attachments are counted as bytes, but not as
+/// packets.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct SocketMetrics {
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// How an `AckError` is reported to the client.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum AckErrorShape {
+    /// Ack the event with `[{"error": payload}]`. Clients get the error
+    /// as the first argument of their ack callback, following the
+    /// `(err, result)` callback convention.
+    Payload,
+    /// Send an `Error` packet carrying the payload. The JS client
+    /// emits these as an `error` event on the socket, not through the
+    /// ack callback.
+    ErrorPacket,
+}
+
+impl Default for AckErrorShape {
+    fn default() -> AckErrorShape {
+        AckErrorShape::Payload
+    }
+}
+
+/// What to do when a client sends a malformed packet. The handler
+/// set with `Socket::on_error` is called either way.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum OnMalformed {
+    /// Send the client an `Error` packet describing the problem. v5
+    /// clients take error packets to be connect errors, so nothing is
+    /// sent to them.
+    Echo,
+    /// Disconnect the client.
+    Disconnect,
+    /// Drop the packet silently.
+    Ignore,
+}
+
+impl Default for OnMalformed {
+    fn default() -> OnMalformed {
+        OnMalformed::Echo
+    }
+}
 
 #[derive(Clone)]
 pub struct Socket {
     socket: socket::Socket,
-    callbacks: Arc<RwLock<HashMap<String, Box<Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data>>>>>,
-    acks: Arc<Mutex<HashMap<usize, Box<Fn(Option<Value>, Option<Vec<Vec<u8>>>)>>>>,
+    id: Arc<RwLock<Option<String>>>,
+    callbacks: Arc<RwLock<HashMap<String, Arc<Fn(&Socket, Vec<Value>, Option<Vec<Vec<u8>>>) -> Result<Vec<Data>, AckError>>>>>,
+    // handlers installed from the defaults of the client's namespace,
+    // replaced when it switches namespaces.
+    namespace_callbacks: Arc<Mutex<HashMap<String, Arc<Fn(&Socket, Vec<Value>, Option<Vec<Vec<u8>>>) -> Result<Vec<Data>, AckError>>>>>,
+    // keyed by ack id, along with the order the acks were asked for in.
+    acks: Arc<Mutex<HashMap<u64, (usize, Box<Fn(Result<AckResponse, AckError>)>)>>>,
+    ack_seq: Arc<AtomicUsize>,
+    waiters: Arc<Mutex<HashMap<String, Vec<(usize, mpsc::Sender<(Vec<Value>, Option<Vec<Vec<u8>>>)>)>>>>,
+    last_waiter_id: Arc<AtomicUsize>,
+    max_pending_acks: Arc<RwLock<Option<usize>>>,
     rooms_joined: Arc<RwLock<Vec<String>>>,
-    server_rooms: Arc<RwLock<HashMap<String, Vec<Socket>>>>,
+    adapter: Arc<Adapter>,
+    namespaces: Arc<RwLock<HashMap<String, Namespace>>>,
+    room_events: Arc<RwLock<Option<Box<Fn(RoomEvent)>>>>,
     cur_packet: Arc<RwLock<Option<Packet>>>,
-    last_ack_id: Arc<AtomicUsize>,
+    // attachments still to come of a packet dropped by the rate limit.
+    dropped_attachments: Arc<AtomicUsize>,
+    last_ack_id: Arc<Mutex<u64>>,
+    last_attachments_num: Arc<AtomicUsize>,
     namespace: Arc<RwLock<Option<String>>>,
-    on_close: Arc<RwLock<Option<Box<Fn()>>>>,
+    handshake: Arc<RwLock<Handshake>>,
+    requested_subprotocol: Arc<RwLock<Option<String>>>,
+    session_key: Arc<RwLock<Option<(Vec<u8>, Duration)>>>,
+    resumed_from: Arc<RwLock<Option<String>>>,
+    data: Arc<RwLock<DataBag>>,
+    counters: Arc<Counters>,
+    handler_stats: Arc<Mutex<HashMap<String, HandlerStat>>>,
+    json_format: Arc<RwLock<JsonFormat>>,
+    protocol: Arc<RwLock<ProtocolVersion>>,
+    ack_error_shape: Arc<RwLock<AckErrorShape>>,
+    on_malformed: Arc<RwLock<OnMalformed>>,
+    lenient: Arc<AtomicBool>,
+    max_message_bytes: Arc<RwLock<Option<usize>>>,
+    decompress_attachments: Arc<AtomicBool>,
+    strict_events: Arc<AtomicBool>,
+    empty_acks: Arc<AtomicBool>,
+    allowed_namespaces: Arc<RwLock<Vec<String>>>,
+    receipts: Arc<AtomicBool>,
+    events_received: Arc<AtomicUsize>,
+    last_activity: Arc<Mutex<Instant>>,
+    heartbeat: Arc<AtomicUsize>,
+    latency: Arc<Mutex<Option<Duration>>>,
+    closed: Arc<AtomicBool>,
+    rate_limit: Arc<Mutex<Option<TokenBucket>>>,
+    outbound: Arc<RwLock<Vec<Box<Fn(&mut Packet)>>>>,
+    on_close: Arc<RwLock<Option<Box<Fn(&CloseReason)>>>>,
+    on_transport_error: Arc<RwLock<Option<Box<Fn(&TransportError)>>>>,
+    traffic: Arc<RwLock<Option<TrafficRecorder>>>,
+    // the server's list of connected clients, left when closing.
+    server_clients: Arc<RwLock<Vec<Socket>>>,
+    on_error: Arc<RwLock<Option<Box<Fn(&Error)>>>>,
 }
 
 unsafe impl Send for Socket {}
@@ -27,239 +314,2185 @@ unsafe impl Sync for Socket {}
 impl Socket {
     #[doc(hidden)]
     pub fn new(socket: socket::Socket,
-               server_rooms: Arc<RwLock<HashMap<String, Vec<Socket>>>>)
+               adapter: Arc<Adapter>,
+               namespaces: Arc<RwLock<HashMap<String, Namespace>>>,
+               room_events: Arc<RwLock<Option<Box<Fn(RoomEvent)>>>>,
+               traffic: Arc<RwLock<Option<TrafficRecorder>>>,
+               server_clients: Arc<RwLock<Vec<Socket>>>)
                -> Socket {
         let so = Socket {
             socket: socket.clone(),
+            id: Arc::new(RwLock::new(None)),
             callbacks: Arc::new(RwLock::new(HashMap::new())),
+            namespace_callbacks: Arc::new(Mutex::new(HashMap::new())),
             acks: Arc::new(Mutex::new(HashMap::new())),
+            ack_seq: Arc::new(AtomicUsize::new(0)),
+            waiters: Arc::new(Mutex::new(HashMap::new())),
+            last_waiter_id: Arc::new(AtomicUsize::new(0)),
+            max_pending_acks: Arc::new(RwLock::new(None)),
             rooms_joined: Arc::new(RwLock::new(Vec::new())),
-            server_rooms: server_rooms,
+            adapter: adapter,
+            namespaces: namespaces,
+            room_events: room_events,
             namespace: Arc::new(RwLock::new(None)),
+            handshake: Arc::new(RwLock::new(Handshake::default())),
+            requested_subprotocol: Arc::new(RwLock::new(None)),
+            session_key: Arc::new(RwLock::new(None)),
+            resumed_from: Arc::new(RwLock::new(None)),
+            data: Arc::new(RwLock::new(DataBag::default())),
+            counters: Arc::new(Counters::default()),
+            handler_stats: Arc::new(Mutex::new(HashMap::new())),
+            json_format: Arc::new(RwLock::new(JsonFormat::default())),
+            protocol: Arc::new(RwLock::new(ProtocolVersion::default())),
+            ack_error_shape: Arc::new(RwLock::new(AckErrorShape::default())),
+            on_malformed: Arc::new(RwLock::new(OnMalformed::default())),
+            lenient: Arc::new(AtomicBool::new(false)),
+            max_message_bytes: Arc::new(RwLock::new(None)),
+            decompress_attachments: Arc::new(AtomicBool::new(false)),
+            strict_events: Arc::new(AtomicBool::new(false)),
+            empty_acks: Arc::new(AtomicBool::new(true)),
+            allowed_namespaces: Arc::new(RwLock::new(vec![])),
+            receipts: Arc::new(AtomicBool::new(false)),
+            events_received: Arc::new(AtomicUsize::new(0)),
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            heartbeat: Arc::new(AtomicUsize::new(0)),
+            latency: Arc::new(Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
+            rate_limit: Arc::new(Mutex::new(None)),
+            outbound: Arc::new(RwLock::new(vec![])),
             cur_packet: Arc::new(RwLock::new(None)),
-            last_ack_id: Arc::new(AtomicUsize::new(0)),
+            dropped_attachments: Arc::new(AtomicUsize::new(0)),
+            last_ack_id: Arc::new(Mutex::new(0)),
+            last_attachments_num: Arc::new(AtomicUsize::new(0)),
             on_close: Arc::new(RwLock::new(None)),
+            on_transport_error: Arc::new(RwLock::new(None)),
+            traffic: traffic,
+            server_clients: server_clients,
+            on_error: Arc::new(RwLock::new(None)),
         };
         let cl = so.clone();
 
         socket.on_message(move |bytes| {
+            so.touch();
+            so.counters.bytes_in.fetch_add(bytes.len(), Relaxed);
+
+            if let Some(max) = *so.max_message_bytes.read().unwrap() {
+                if bytes.len() > max {
+                    // the packet an oversized attachment belongs to
+                    // can't be completed anymore.
+                    *so.cur_packet.write().unwrap() = None;
+                    so.dropped_attachments.store(0, Relaxed);
+                    so.reject(Error::MessageTooLarge(bytes.len()),
+                              DisconnectReason::MessageTooLarge);
+                    return;
+                }
+            }
+
+            if so.dropped_attachments.load(Relaxed) > 0 {
+                so.record_attachment(Direction::Inbound, bytes.len());
+                so.dropped_attachments.fetch_sub(1, Relaxed);
+                return;
+            }
+
+            // engine.io doesn't tell us whether a frame was sent as
+            // binary, so while a packet is buffered every frame is
+            // taken to be its next attachment. Text frames interleaved
+            // with the attachments can't be told apart from them, and
+            // aren't supported; socket.io clients don't send any.
             if so.has_buffered_packet() {
-                let mut packet = so.cur_packet.write().unwrap();
-                if packet.as_mut().unwrap().add_attachment(bytes.to_vec()) {
+                so.record_attachment(Direction::Inbound, bytes.len());
+                let complete = add_attachment(&mut so.cur_packet.write().unwrap(), bytes);
+                if let Some(packet) = complete {
                     // received all attachments, fire relevant
                     // callback/ack
-                    let packet = packet.take().unwrap();
                     match packet.opcode {
-                        Opcode::BinaryEvent => {
-                            let ack = so.fire_callback(&packet);
-
-                            if let Some(id) = packet.id {
-                                if let Some(ack) = ack {
-                                    let (json, binary) = encode_data(ack);
-                                    so.send_ack(id, json, binary);
-                                } else {
-                                    so.send("[]".to_string().into_bytes());
-                                }
-                            }
-                        }
+                        Opcode::BinaryEvent => so.dispatch_event(&packet),
                         Opcode::BinaryAck => so.fire_ack(&packet),
                         _ => unreachable!(),
                     }
-                } else {
-                    return;
                 }
+                // the frame was an attachment, don't try to decode
+                // it as a packet.
+                return;
             }
 
-            let packet: Packet = match Packet::from_bytes(bytes) {
+            let decoded = if so.lenient.load(Relaxed) {
+                Packet::from_bytes_lenient(bytes)
+            } else {
+                Packet::from_bytes(bytes)
+            };
+
+            if !so.take_rate_limit_token() {
+                so.fire_error(&Error::RateLimited);
+                // the attachments of a dropped packet are dropped along
+                // with it, rather than taken for packets.
+                if let Ok(ref packet) = decoded {
+                    so.dropped_attachments.store(packet.attachments_num, Relaxed);
+                }
+                return;
+            }
+            let packet: Packet = match decoded {
                 Ok(p) => p,
                 Err(e) => {
-                    so.send(Packet::new_error(so.namespace.read().unwrap().clone(),
-                                              e).encode().into_bytes());
+                    so.reject(e, DisconnectReason::MalformedPacket);
                     return;
-                }, //TODO: emit error here
+                },
             };
 
+            so.record_packet(Direction::Inbound, &packet);
             match packet.opcode {
-                Opcode::Disconnect => {so.clone().close(); return;},
-                Opcode::Event => {
-                    let ack = so.fire_callback(&packet);
-
-                    if let Some(id) = packet.id {
-                        if let Some(ack) = ack {
-                            let (json, binary) = encode_data(ack);
-                            so.send_ack(id, json, binary);
-                        } else {
-                            so.send("[]".to_string().into_bytes());
-                        }
-                    }
-                }
-                Opcode::Ack => so.fire_ack(&packet),
-                Opcode::Connect => {
-                    *so.namespace.write().unwrap() = packet.namespace.clone();
+                // v5 clients disconnect from each namespace
+                // separately, only close the connection when leaving
+                // the one this socket is connected to.
+                Opcode::Disconnect if so.protocol() == ProtocolVersion::V5 &&
+                                      packet.namespace != so.namespace() => {},
+                Opcode::Disconnect => {
+                    so.shutdown(Initiator::Client, "client disconnect");
+                    return;
                 },
+                // a binary packet without any attachments is
+                // handled just like its plain counterpart.
+                Opcode::Event | Opcode::BinaryEvent if packet.attachments_num == 0 => {
+                    so.dispatch_event(&packet)
+                }
+                Opcode::Ack | Opcode::BinaryAck if packet.attachments_num == 0 => {
+                    so.fire_ack(&packet)
+                }
+                Opcode::Connect => so.connect(&packet),
                 _ => {},
             }
 
-            if packet.has_attachments() {
-                if packet.opcode == Opcode::BinaryEvent || packet.opcode == Opcode::BinaryAck {
-                    // BinaryEvent and BinaryAck
-                    // can have attachments
-                    let mut cur = so.cur_packet.write().unwrap();
-                    *cur = Some(packet);
-                }
-                return;
+            // wait for the attachments announced by the packet. Only
+            // BinaryEvent and BinaryAck can announce any, `from_bytes`
+            // rejects other packets that do.
+            if packet.attachments_num > 0 {
+                let mut cur = so.cur_packet.write().unwrap();
+                *cur = Some(packet);
             }
         });
 
         let so2 = cl.clone();
-        socket.on_close(move |_| {
-            if let Some(ref func) = *so2.on_close.read().unwrap() {
-                func();
+        socket.on_close(move |reason| {
+            let initiator = engine_close_initiator(reason);
+            // connections closed by the server are reported to
+            // engine.io under the server's reason, and are closed
+            // already.
+            if initiator == Initiator::Transport && !so2.is_closed() {
+                if let Some(ref func) = *so2.on_transport_error.read().unwrap() {
+                    func(&TransportError { reason: reason.to_string() });
+                }
             }
+            so2.closed_by(initiator, reason);
         });
 
         cl
     }
 
-    fn fire_callback(&self, packet: &Packet) -> Option<Vec<Data>> {
-        let event_arr: &Vec<Value> = match packet.data.as_ref().unwrap() {
-            &Value::Array(ref v) => v,
-            _ => panic!("Event packet doesn't have an array payload"),
+    fn dispatch_event(&self, packet: &Packet) {
+        self.handle_event(packet);
+        if self.receipts.load(Relaxed) {
+            let seq = self.events_received.fetch_add(1, Relaxed) + 1;
+            self.send_packet(receipt_packet(self.namespace(), seq as u64));
+        }
+    }
+
+    fn handle_event(&self, packet: &Packet) {
+        let ack = self.fire_callback(packet);
+
+        if ack.is_none() && self.strict_events.load(Relaxed) {
+            // events without a name were reported by `fire_callback`.
+            let name = match split_event(packet) {
+                Ok((name, _)) => name,
+                Err(_) => return,
+            };
+            let error = Error::UnknownEvent(name);
+            self.fire_error(&error);
+            let shape = *self.ack_error_shape.read().unwrap();
+            let reply = unknown_event_reply(self.protocol(), shape, self.namespace(), packet.id, error);
+            if let Some(reply) = reply {
+                self.send_packet(reply);
+            }
+            return;
+        }
+
+        if let Some(id) = packet.id {
+            let empty_acks = self.empty_acks.load(Relaxed);
+            let shape = *self.ack_error_shape.read().unwrap();
+            let reply = event_ack(ack, empty_acks, shape, self.namespace(), id);
+            if let Some((reply, attachments)) = reply {
+                self.send_packet_with_attachments(reply, attachments);
+            }
+        }
+    }
+
+    fn fire_callback(&self, packet: &Packet) -> Option<Result<Vec<Data>, AckError>> {
+        // `Packet::from_bytes` rejects events without a name, but
+        // packets built some other way may still lack one.
+        let (name, event_arr) = match split_event(packet) {
+            Ok(event) => event,
+            Err(e) => {
+                self.fire_error(&e);
+                return None;
+            }
         };
+        self.last_attachments_num.store(packet.attachments_num, Relaxed);
+
+        let waiters = self.waiters.lock().unwrap().remove(&name);
+        for (_, tx) in waiters.unwrap_or(vec![]) {
+            let args = event_arr.to_vec();
+            let _ = tx.send((args, self.attachments(packet)));
+        }
 
-        let ref event = event_arr[0];
+        // the handler is called without holding the callbacks lock,
+        // so it can register handlers of its own.
+        let func = self.callbacks.read().unwrap().get(&name).cloned();
+        if let Some(func) = func {
+            let args = event_arr.to_vec();
+            let attachments = self.attachments(packet);
 
-        let callbacks = self.callbacks.read().unwrap();
-        if let Some(func) = callbacks.get(&event.to_string()) {
-            Some(func(event_arr.into_iter().skip(1).map(|v| v.clone()).collect(),
-                      packet.get_attachments()))
+            let start = Instant::now();
+            let result = call_handler(&name, || func(self, args, attachments));
+            self.handler_stats
+                .lock()
+                .unwrap()
+                .entry(name.clone())
+                .or_insert_with(HandlerStat::default)
+                .record(start.elapsed());
+
+            match result {
+                Ok(ack) => Some(ack),
+                Err(error) => {
+                    self.fire_error(&error);
+                    Some(Err(AckError::Rejected(Value::String(error.to_string()))))
+                }
+            }
         } else {
             None
         }
     }
 
+    fn connect(&self, packet: &Packet) {
+        let protocol = self.protocol();
+        let allowed = namespace_allowed(&self.allowed_namespaces.read().unwrap(),
+                                        packet.namespace.as_ref().map(|nsp| &nsp[..]));
+        if !allowed {
+            let message = Value::String("Invalid namespace".to_string());
+            let data = connect_error_payload(protocol, message);
+            self.send_packet(Packet::new_connect_error(packet.namespace.clone(), data));
+            return;
+        }
+
+        if protocol == ProtocolVersion::V5 {
+            self.handshake.write().unwrap().auth = packet.data.clone();
+        }
+
+        let name = packet.namespace.clone().unwrap_or(DEFAULT_NAMESPACE.to_string());
+        let namespace = self.namespaces.read().unwrap().get(&name).cloned();
+        if let Some(Err(error)) = namespace.map(|namespace| namespace.run_middleware(self)) {
+            let data = connect_error_payload(protocol, error);
+            self.send_packet(Packet::new_connect_error(packet.namespace.clone(), data));
+            return;
+        }
+
+        self.switch_namespace(packet.namespace.clone());
+        if let Some(reply) = connect_reply(protocol, packet.namespace.clone(), &self.id()) {
+            self.send_packet(reply);
+        }
+    }
+
+    fn switch_namespace(&self, nsp: Option<String>) {
+        let mut namespaces = self.namespaces.write().unwrap();
+        let old = self.namespace().unwrap_or(DEFAULT_NAMESPACE.to_string());
+        if let Some(namespace) = namespaces.get(&old) {
+            namespace.remove_socket(&self.id());
+        }
+
+        let new = nsp.clone().unwrap_or(DEFAULT_NAMESPACE.to_string());
+        let namespace = namespaces.entry(new.clone())
+            .or_insert_with(|| Namespace::new(new, self.adapter.clone()));
+        namespace.install_callbacks(self);
+        namespace.add_socket(self.clone());
+
+        // rooms are scoped to namespaces, so the client's rooms move
+        // along with it.
+        let mut rooms = self.rooms_joined.read().unwrap().clone();
+        rooms.push(self.id());
+        for room in rooms.iter() {
+            self.adapter.remove(self, room);
+        }
+        *self.namespace.write().unwrap() = nsp;
+        for room in rooms.iter() {
+            self.adapter.add(self, room);
+        }
+    }
+
+    fn fire_error(&self, error: &Error) {
+        if let Some(ref func) = *self.on_error.read().unwrap() {
+            func(error);
+        }
+    }
+
     fn fire_ack(&self, packet: &Packet) {
-        let map = self.acks.lock();
-        if let Some(callback) = map.unwrap().remove(&packet.id.unwrap()) {
-            callback(packet.data.clone(), packet.get_attachments().clone());
+        // don't hold the lock while calling the callback, it may well
+        // emit another event expecting an ack.
+        let callback = self.acks.lock().unwrap().remove(&packet.id.unwrap());
+        if let Some((_, callback)) = callback {
+            callback(Ok(AckResponse {
+                data: packet.data.clone(),
+                attachments: self.attachments(packet),
+            }));
         }
     }
 
+    /// Returns how many attachments of the packet being received have
+    /// arrived so far, and how many it announced, as `(received,
+    /// expected)`. `None` if no packet is waiting for attachments.
+    pub fn buffered_attachment_progress(&self) -> Option<(usize, usize)> {
+        let cur = self.cur_packet.read().unwrap();
+        cur.as_ref().map(|packet| (packet.attachments_received(), packet.attachments_num))
+    }
+
     #[inline]
     fn has_buffered_packet(&self) -> bool {
         let cur = self.cur_packet.read().unwrap();
         cur.is_some()
     }
 
+    /// Returns the socket's id. Unless the server was set up with
+    /// `Server::with_id_generator`, this is the id of the underlying
+    /// engine.io connection.
     #[inline(always)]
     pub fn id(&self) -> String {
+        match *self.id.read().unwrap() {
+            Some(ref id) => id.clone(),
+            None => self.socket.id(),
+        }
+    }
+
+    // The id engine.io knows the connection by, which differs from
+    // `id` if the server generates its own ids.
+    #[doc(hidden)]
+    pub fn engine_id(&self) -> String {
         self.socket.id()
     }
 
+    #[doc(hidden)]
+    pub fn set_id(&self, id: String) {
+        *self.id.write().unwrap() = Some(id);
+    }
+
+    /// Returns a snapshot of the socket's state, for debugging. All
+    /// of it is read while holding the relevant locks at once, so the
+    /// snapshot is consistent.
+    ///
+    /// engine.io doesn't expose its send buffer, so packets that were
+    /// sent but not flushed to the transport yet can't be reported.
+    pub fn debug_dump(&self) -> SocketDebug {
+        let callbacks = self.callbacks.read().unwrap();
+        let acks = self.acks.lock().unwrap();
+        let rooms = self.rooms_joined.read().unwrap();
+        let namespace = self.namespace.read().unwrap();
+
+        let mut events: Vec<String> = callbacks.keys().cloned().collect();
+        events.sort();
+        let mut pending_acks: Vec<u64> = acks.keys().cloned().collect();
+        pending_acks.sort();
+
+        SocketDebug {
+            id: self.id(),
+            namespace: namespace.clone(),
+            events: events,
+            pending_acks: pending_acks,
+            rooms: rooms.clone(),
+        }
+    }
+
+    /// Returns the number of packets and bytes sent to and received
+    /// from the client so far.
+    pub fn metrics(&self) -> SocketMetrics {
+        SocketMetrics {
+            packets_in: self.counters.packets_in.load(Relaxed) as u64,
+            packets_out: self.counters.packets_out.load(Relaxed) as u64,
+            bytes_in: self.counters.bytes_in.load(Relaxed) as u64,
+            bytes_out: self.counters.bytes_out.load(Relaxed) as u64,
+        }
+    }
+
+    /// Returns how long the handler for each event took to run so
+    /// far, keyed by event name. Handlers run on the thread handling
+    /// the client's messages, so slow handlers hold up every message
+    /// after them.
+    pub fn handler_stats(&self) -> HashMap<String, HandlerStat> {
+        self.handler_stats.lock().unwrap().clone()
+    }
+
+    /// Returns the details of the HTTP request the client connected
+    /// with.
+    pub fn handshake(&self) -> Handshake {
+        self.handshake.read().unwrap().clone()
+    }
+
+    #[doc(hidden)]
+    pub fn set_handshake(&self, handshake: Handshake) {
+        *self.handshake.write().unwrap() = handshake;
+    }
+
+    /// Returns the query parameters the client connected with,
+    /// percent-decoded, see `Handshake::query_params`.
+    pub fn query(&self) -> HashMap<String, String> {
+        self.handshake.read().unwrap().query_params()
+    }
+
+    /// Returns the WebSocket subprotocol the client offered: the one
+    /// required with `Server::with_subprotocol`, or else the first one
+    /// in its `Sec-WebSocket-Protocol` header. This is only the
+    /// client's offer; engine.io writes the upgrade response, and
+    /// doesn't echo the subprotocol back. `None` for clients connected
+    /// over long-polling that haven't upgraded to a WebSocket.
+    pub fn requested_subprotocol(&self) -> Option<String> {
+        self.requested_subprotocol.read().unwrap().clone()
+    }
+
+    #[doc(hidden)]
+    pub fn set_requested_subprotocol(&self, subprotocol: Option<String>) {
+        *self.requested_subprotocol.write().unwrap() = subprotocol;
+    }
+
+    /// Returns the auth payload the client connected with, see
+    /// `Handshake::auth`. v5 clients connecting with an empty auth
+    /// object get `Some` empty object, rather than `None`.
+    pub fn auth(&self) -> Option<Value> {
+        self.handshake.read().unwrap().auth.clone()
+    }
+
+    #[doc(hidden)]
+    pub fn set_session_key(&self, key: Vec<u8>, lifetime: Duration) {
+        *self.session_key.write().unwrap() = Some((key, lifetime));
+    }
+
+    #[doc(hidden)]
+    pub fn set_resumed_from(&self, id: String) {
+        *self.resumed_from.write().unwrap() = Some(id);
+    }
+
+    /// Returns a token the client can send back as the `token` query
+    /// parameter when reconnecting, to show it's resuming this
+    /// connection, see `resumed_from`. The token is signed with the
+    /// key set with `Server::set_session_key`, and expires once the
+    /// lifetime set there elapsed. `None` if no key was set.
+    ///
+    /// The token is a bearer token: it isn't bound to this client, and
+    /// anyone holding it can resume with it, any number of times, until
+    /// it expires. Only send it over a secure transport, and keep the
+    /// lifetime short.
+    pub fn session_token(&self) -> Option<String> {
+        self.session_key.read().unwrap().as_ref().map(|&(ref key, lifetime)| {
+            session::issue(key, &self.id(), session::now() + lifetime.as_secs())
+        })
+    }
+
+    /// Returns the id of the connection the client resumed with a
+    /// valid token from `session_token`, if it did. Restoring the
+    /// state of that connection is up to the application.
+    pub fn resumed_from(&self) -> Option<String> {
+        self.resumed_from.read().unwrap().clone()
+    }
+
+    /// Attach `value` to the socket, e.g. the user the client
+    /// authenticated as, replacing any value of the same type attached
+    /// before. Values are told apart by their type, so wrap common
+    /// types like `String` in a type of their own.
+    pub fn set<T: Any + Send + Sync>(&self, value: T) {
+        self.data.write().unwrap().insert(value);
+    }
+
+    /// Returns the value of type `T` attached with `set`, if any.
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.data.read().unwrap().get()
+    }
+
+    /// Returns the number of attachments the last event received
+    /// from the client declared. Inside an event handler, this can be
+    /// checked against the attachments the handler was given.
+    #[inline]
+    pub fn last_packet_attachments_num(&self) -> usize {
+        self.last_attachments_num.load(Relaxed)
+    }
+
+    /// Returns the namespace the client is currently connected to,
+    /// or `None` for the default namespace.
+    #[inline]
+    pub fn namespace(&self) -> Option<String> {
+        self.namespace.read().unwrap().clone()
+    }
+
     pub fn on<F>(&self, event: String, f: F)
         where F: Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data> + 'static
     {
-        let mut map = self.callbacks.write().unwrap();
-        map.insert(event, Box::new(f));
+        self.on_result(event, move |params, attachments| Ok(f(params, attachments)));
     }
 
-    pub fn join(&self, room: String) {
-        let mut rooms = self.rooms_joined.write().unwrap();
-        if !rooms.contains(&room) {
-            rooms.push(room.clone());
-
-            let mut map = self.server_rooms.write().unwrap();
-            if map.contains_key(&room) {
-                map.get_mut(&room).unwrap().push(self.clone())
-            } else {
-                map.insert(room, vec![self.clone()]);
+    /// Replace the handlers installed from the defaults of the
+    /// client's previous namespace with `defaults`, set with
+    /// `Namespace::on_event`. Handlers registered on the socket itself
+    /// are kept.
+    #[doc(hidden)]
+    pub fn set_namespace_callbacks(&self,
+                                   defaults: Vec<(String, Arc<Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data>>)>) {
+        let mut map = self.callbacks.write().unwrap();
+        let mut installed = self.namespace_callbacks.lock().unwrap();
+        for (event, func) in installed.drain() {
+            if map.get(&event).map_or(false, |current| Arc::ptr_eq(current, &func)) {
+                map.remove(&event);
+            }
+        }
+        for (event, func) in defaults {
+            if map.contains_key(&event) {
+                continue;
             }
+            map.insert(event.clone(),
+                       Arc::new(move |_: &Socket, params, attachments| Ok(func(params, attachments))));
+            installed.insert(event.clone(), map[&event].clone());
         }
     }
 
-    pub fn leave(&self, room: String) {
-        let mut rooms_map = self.server_rooms.write().unwrap();
-        if let Some (_) = rooms_map.remove(&room) {
-            let mut rooms = self.rooms_joined.write().unwrap();
-        }
+    /// Like `on`, but the handler can reject the event by returning
+    /// an `AckError`, which is reported to the client through the
+    /// event's ack as set with `set_ack_error_shape`.
+    pub fn on_result<F>(&self, event: String, f: F)
+        where F: Fn(Vec<Value>, Option<Vec<Vec<u8>>>) -> Result<Vec<Data>, AckError> + 'static
+    {
+        let mut map = self.callbacks.write().unwrap();
+        map.insert(event, Arc::new(move |_: &Socket, params, attachments| f(params, attachments)));
     }
 
-    fn send_ack(&self, id: usize, json: Value, attachments: Vec<Vec<u8>>) {
-        self.send(Packet::new_ack(self.namespace.read().unwrap().clone(), id, attachments.len(), json).encode()
-                  .into_bytes());
-        for b in attachments {
-            self.send(b);
+    /// Like `on`, but the handler is also passed the socket that
+    /// received the event, e.g. to join a room in response to it.
+    /// The socket is passed by reference rather than captured by the
+    /// handler, so registering handlers doesn't keep the socket alive.
+    pub fn on_with_socket<F>(&self, event: String, f: F)
+        where F: Fn(&Socket, Vec<Value>, Option<Vec<Vec<u8>>>) -> Vec<Data> + 'static
+    {
+        let mut map = self.callbacks.write().unwrap();
+        map.insert(event, Arc::new(move |so: &Socket, params, attachments| Ok(f(so, params, attachments))));
+    }
+
+    /// Like `on`, for events uploading a single file as their only
+    /// attachment. Events with any other number of attachments are
+    /// rejected through their ack, without calling the handler.
+    pub fn on_upload<F>(&self, event: String, f: F)
+        where F: Fn(Upload) -> Vec<Data> + 'static
+    {
+        self.on_result(event, move |args, attachments| {
+            upload(args, attachments).map(|upload| f(upload))
+        });
+    }
+
+    /// Returns the names of the events handlers are registered for,
+    /// sorted.
+    pub fn registered_events(&self) -> Vec<String> {
+        let mut events: Vec<String> = self.callbacks.read().unwrap().keys().cloned().collect();
+        events.sort();
+        events
+    }
+
+    /// Set how errors returned by handlers are reported to the
+    /// client. Defaults to `AckErrorShape::Payload`.
+    pub fn set_ack_error_shape(&self, shape: AckErrorShape) {
+        *self.ack_error_shape.write().unwrap() = shape;
+    }
+
+    /// Set what to do when the client sends a malformed packet.
+    /// Defaults to `OnMalformed::Echo`.
+    pub fn set_on_malformed(&self, policy: OnMalformed) {
+        *self.on_malformed.write().unwrap() = policy;
+    }
+
+    /// Set whether to ignore whitespace around packets from the client,
+    /// see `Packet::from_bytes_lenient`. Defaults to `false`.
+    pub fn set_lenient_parsing(&self, lenient: bool) {
+        self.lenient.store(lenient, Relaxed);
+    }
+
+    /// Reject messages from the client larger than `max` bytes,
+    /// attachments included. engine.io has already buffered a message
+    /// when it's handed to the socket, but oversized messages aren't
+    /// parsed: they fire the `on_error` callback with
+    /// `Error::MessageTooLarge`, and are then handled like malformed
+    /// packets, see `set_on_malformed`.
+    pub fn set_max_message_bytes(&self, max: Option<usize>) {
+        *self.max_message_bytes.write().unwrap() = max;
+    }
+
+    /// Set whether attachments the client sent compressed, see
+    /// `Data::CompressedBinary`, are decompressed before being passed
+    /// to handlers. A decompressed attachment can't grow past the
+    /// socket's message limit, see `set_max_message_bytes`, or 16 MiB
+    /// if there's none; larger ones are passed on compressed. Defaults
+    /// to `false`.
+    pub fn set_decompress_attachments(&self, enabled: bool) {
+        self.decompress_attachments.store(enabled, Relaxed);
+    }
+
+    fn attachments(&self, packet: &Packet) -> Option<Vec<Vec<u8>>> {
+        let max = if self.decompress_attachments.load(Relaxed) {
+            Some(self.max_message_bytes.read().unwrap().unwrap_or(MAX_DECOMPRESSED_BYTES))
+        } else {
+            None
+        };
+        decode_attachments(packet, max)
+    }
+
+    // Reports an unusable message from the client, as set with
+    // `set_on_malformed`.
+    fn reject(&self, e: Error, reason: DisconnectReason) {
+        self.fire_error(&e);
+        match malformed_policy(*self.on_malformed.read().unwrap(), self.protocol()) {
+            OnMalformed::Echo => self.send_packet(Packet::new_error(self.namespace(), e)),
+            OnMalformed::Disconnect => self.disconnect(reason),
+            OnMalformed::Ignore => {}
         }
     }
 
-    #[inline(always)]
+    /// Reject events no handler is registered for, instead of
+    /// acknowledging them with no data. Rejected events fire the
+    /// `on_error` callback with `Error::UnknownEvent`, and are
+    /// reported to the client through their ack, or with an `Error`
+    /// packet to v4 clients if they didn't ask for one. Defaults to
+    /// `false`.
+    pub fn set_strict_events(&self, strict: bool) {
+        self.strict_events.store(strict, Relaxed);
+    }
+
+    /// Set whether events asking for an ack are acknowledged with no
+    /// data when no handler is registered for them, or their handler
+    /// returns no data. If not, such events aren't acknowledged at
+    /// all, so clients can tell them apart from handled ones. Defaults
+    /// to `true`.
+    pub fn set_empty_acks(&self, enabled: bool) {
+        self.empty_acks.store(enabled, Relaxed);
+    }
+
+    /// Set whether to send the client a receipt after each event it
+    /// sent has been dispatched, whether or not the event asked for an
+    /// ack. Receipts are events named `RECEIPT_EVENT`, carrying the
+    /// number of events received from the client so far, counting from
+    /// 1, so the client can tell which of its events were processed.
+    /// Only clients knowing this convention make use of them. Defaults
+    /// to `false`.
+    pub fn set_receipts(&self, enabled: bool) {
+        self.receipts.store(enabled, Relaxed);
+    }
+
     #[doc(hidden)]
-    pub fn send(&self, data: Vec<u8>) {
-        self.socket.send(data);
+    pub fn set_allowed_namespaces(&self, namespaces: Vec<String>) {
+        *self.allowed_namespaces.write().unwrap() = namespaces;
     }
 
-    /// Emit an event to the client, with the name `event`.
-    pub fn emit(&self, event: Value, params: Option<Vec<Data>>) {
-        let mut all_event_params: Vec<_> = vec![Data::JSON(event)];
-        if params.is_some() {
-            all_event_params.extend_from_slice(&params.unwrap());
+    /// Returns when a packet was last sent to or received from the
+    /// client.
+    pub fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().unwrap()
+    }
+
+    #[inline]
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Emit a `ping` event to the client every `interval`, with the
+    /// current time in milliseconds since the Unix epoch as its
+    /// argument, and measure how long the client takes to acknowledge
+    /// it. The last round-trip time is returned by `latency`. Calling
+    /// this again replaces the previous heartbeat, which stops once
+    /// the connection closes.
+    pub fn start_heartbeat(&self, interval: Duration) {
+        let generation = self.heartbeat.fetch_add(1, Relaxed) + 1;
+        let so = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                if so.is_closed() || so.heartbeat.load(Relaxed) != generation {
+                    return;
+                }
+                so.ping();
+            }
+        });
+    }
+
+    fn ping(&self) {
+        let sent = Instant::now();
+        let since_epoch = UNIX_EPOCH.elapsed().unwrap_or(Duration::from_secs(0));
+        let millis = since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1_000_000;
+        let latency = self.latency.clone();
+        self.emit_ack(Value::String("ping".to_string()),
+                      Some(vec![Data::JSON(Value::U64(millis))]),
+                      move |_, _| *latency.lock().unwrap() = Some(sent.elapsed()));
+    }
+
+    /// Returns the round-trip time of the last `ping` acknowledged by
+    /// the client, see `start_heartbeat`.
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency.lock().unwrap()
+    }
+
+    /// Limit the client to `max_packets_per_sec` packets per second.
+    /// Packets over the limit are dropped, firing the `on_error`
+    /// callback with `Error::RateLimited`. Attachments don't count
+    /// towards the limit, and are dropped along with their packet.
+    pub fn set_rate_limit(&self, max_packets_per_sec: u32) {
+        *self.rate_limit.lock().unwrap() = Some(TokenBucket::new(max_packets_per_sec,
+                                                                 Instant::now()));
+    }
+
+    /// Allow the client to send packets again at the full rate
+    /// limit, as if it hadn't sent any packets recently.
+    pub fn reset_rate_limit(&self) {
+        if let Some(ref mut bucket) = *self.rate_limit.lock().unwrap() {
+            bucket.reset(Instant::now());
         }
+    }
+
+    /// Remove the rate limit set with `set_rate_limit`.
+    pub fn clear_rate_limit(&self) {
+        *self.rate_limit.lock().unwrap() = None;
+    }
 
-        let (json, binary_vec) = encode_data(all_event_params);
-        self.send(Packet::new_event(self.namespace.read().unwrap().clone(), None, binary_vec.len(), json)
-            .encode()
-            .into_bytes());
-        for binary in binary_vec {
-            self.send(binary);
+    fn take_rate_limit_token(&self) -> bool {
+        match *self.rate_limit.lock().unwrap() {
+            Some(ref mut bucket) => bucket.take(Instant::now()),
+            None => true,
         }
     }
 
-    /// Emit an event to the client, and ask the client for an
-    /// acknowledgment. Once received, call `on_ack`.
-    pub fn emit_ack<F>(&self, event: Value, params: Option<Vec<Data>>, on_ack: F)
-        where F: Fn(Option<Value>, Option<Vec<Vec<u8>>>) + 'static
+    /// Set callback to be called when the client sends a malformed
+    /// packet or a message over the size limit, or exceeds its rate
+    /// limit, or when an event handler panics.
+    pub fn on_error<F>(&self, f: F)
+        where F: Fn(&Error) + 'static
     {
-        let mut all_event_params: Vec<_> = vec![Data::JSON(event)];
-        if params.is_some() {
-            all_event_params.extend_from_slice(&params.unwrap());
-        }
+        *self.on_error.write().unwrap() = Some(Box::new(f));
+    }
 
-        let ack_id = self.new_ack_id();
+    pub fn join(&self, room: String) {
         {
-            let mut map = self.acks.lock().unwrap();
-            map.insert(ack_id, Box::new(on_ack));
-        }
-        let (json, binary_vec) = encode_data(all_event_params);
-        self.send(Packet::new_event(self.namespace.read().unwrap().clone(), Some(ack_id), binary_vec.len(), json)
-            .encode()
-            .into_bytes());
-        for binary in binary_vec {
-            self.send(binary);
+            let mut rooms = self.rooms_joined.write().unwrap();
+            if !add_room(&mut rooms, &room) {
+                return;
+            }
+            self.adapter.add(self, &room);
         }
+        self.fire_room_event(RoomEvent::Joined {
+            socket_id: self.id(),
+            room: room,
+        });
     }
 
-    fn new_ack_id(&self) -> usize {
-        self.last_ack_id.fetch_add(1, Relaxed)
+    pub fn leave(&self, room: String) {
+        self.adapter.remove(self, &room);
+        let joined = remove_room(&mut self.rooms_joined.write().unwrap(), &room);
+        if joined {
+            self.fire_room_event(RoomEvent::Left {
+                socket_id: self.id(),
+                room: room,
+            });
+        }
     }
 
-    /// Close the connection to the client.
-    pub fn close(&mut self) {
-        self.socket.close("close()");
-        let rooms_joined = self.rooms_joined.read().unwrap();
-
-        for room in rooms_joined.iter() {
-            let mut map = self.server_rooms.write().unwrap();
-            let mut clients = map.get_mut(room).unwrap();
-            let mut i = 0;
-
-            for (index, so) in clients.iter().enumerate() {
-                if so.id() == self.id() {
-                    i = index;
-                    break;
-                }
+    /// Leave the room `from` and join the room `to` at once, e.g. to
+    /// move a client from a lobby to a game. Broadcasts to either room
+    /// see the client in exactly one of them, if the adapter supports
+    /// it, see `Adapter::move_room`. The client joins `to` even if it
+    /// wasn't in `from`.
+    pub fn move_room(&self, from: &str, to: &str) {
+        if from == to {
+            return self.join(to.to_string());
+        }
+        let (left, joined) = {
+            let mut rooms = self.rooms_joined.write().unwrap();
+            self.adapter.move_room(self, from, to);
+            (remove_room(&mut rooms, from), add_room(&mut rooms, to))
+        };
+        if left {
+            self.fire_room_event(RoomEvent::Left {
+                socket_id: self.id(),
+                room: from.to_string(),
+            });
+        }
+        if joined {
+            self.fire_room_event(RoomEvent::Joined {
+                socket_id: self.id(),
+                room: to.to_string(),
+            });
+        }
+    }
+
+    /// Returns the rooms joined with `join`, in the order they were
+    /// joined.
+    pub fn rooms(&self) -> Vec<String> {
+        self.rooms_joined.read().unwrap().clone()
+    }
+
+    fn fire_room_event(&self, event: RoomEvent) {
+        if let Some(ref func) = *self.room_events.read().unwrap() {
+            func(event);
+        }
+    }
+
+    /// Returns a `BroadcastOperator` emitting to every client in
+    /// `room` of this client's namespace, except this one.
+    pub fn to(&self, room: &str) -> BroadcastOperator {
+        BroadcastOperator::new(self.adapter.clone())
+            .in_namespace(self.namespace())
+            .to(room)
+            .except(&self.id())
+    }
+
+    /// Like `to`, but fails unless this client joined `room` with
+    /// `join`, so clients can only broadcast to rooms they're in.
+    pub fn to_room_broadcast(&self, room: &str) -> Result<BroadcastOperator, NotInRoomError> {
+        try!(check_joined(&self.rooms_joined.read().unwrap(), room));
+        Ok(self.to(room))
+    }
+
+    /// Returns a `BroadcastOperator` emitting to the room named after
+    /// the socket's id, which only this client is in. Unlike `to`,
+    /// the client itself isn't excluded.
+    pub fn to_self_room(&self) -> BroadcastOperator {
+        BroadcastOperator::new(self.adapter.clone())
+            .in_namespace(self.namespace())
+            .to(&self.id())
+    }
+
+    /// Set how JSON payloads of packets sent to the client are
+    /// serialized.
+    pub fn set_json_format(&self, format: JsonFormat) {
+        *self.json_format.write().unwrap() = format;
+    }
+
+    #[doc(hidden)]
+    pub fn set_protocol(&self, protocol: ProtocolVersion) {
+        *self.protocol.write().unwrap() = protocol;
+    }
+
+    /// Returns the protocol revision spoken with the client.
+    pub fn protocol(&self) -> ProtocolVersion {
+        *self.protocol.read().unwrap()
+    }
+
+    /// Returns the socket.io revision the client connected with, going
+    /// by the engine.io revision sent in its handshake request. Falls
+    /// back to `protocol` for clients that didn't send a known one.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.handshake.read().unwrap().protocol_version().unwrap_or(self.protocol())
+    }
+
+    /// Returns the engine.io revision the client connected with, as
+    /// sent in the `EIO` query parameter of its handshake request.
+    /// Falls back to the one `protocol` is carried over for clients
+    /// that didn't send one.
+    pub fn engine_io_version(&self) -> usize {
+        self.handshake
+            .read()
+            .unwrap()
+            .engine_io_version()
+            .unwrap_or(self.protocol().engine_io_version())
+    }
+
+    #[inline(always)]
+    #[doc(hidden)]
+    pub fn send(&self, data: Vec<u8>) {
+        self.touch();
+        self.counters.bytes_out.fetch_add(data.len(), Relaxed);
+        self.socket.send(data);
+    }
+
+    /// Sends `bytes` to the client verbatim, e.g. to forward a frame
+    /// captured from another connection. Nothing is checked besides
+    /// whether the connection is still open: `bytes` isn't parsed,
+    /// outbound hooks aren't run, traffic isn't recorded, and the
+    /// client drops its connection if `bytes` isn't a valid packet for
+    /// its namespace.
+    pub fn send_raw(&self, bytes: Vec<u8>) -> Result<(), SendError> {
+        if self.is_closed() {
+            return Err(SendError::Closed);
+        }
+        self.send(bytes);
+        Ok(())
+    }
+
+    #[inline]
+    fn send_packet(&self, packet: Packet) {
+        self.send_packet_with_attachments(packet, vec![]);
+    }
+
+    fn send_packet_with_attachments(&self, packet: Packet, attachments: Vec<Vec<u8>>) {
+        for frame in self.encode_frames(packet, attachments) {
+            self.send(frame);
+        }
+    }
+
+    // Encodes `packet` into the frames sent to the client: the packet
+    // itself, followed by its attachments.
+    fn encode_frames(&self, mut packet: Packet, attachments: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        for f in self.outbound.read().unwrap().iter() {
+            f(&mut packet);
+        }
+        // outbound hooks can't change how many attachments are sent
+        packet.set_attachments_num(attachments.len());
+
+        self.record_packet(Direction::Outbound, &packet);
+        for b in attachments.iter() {
+            self.record_attachment(Direction::Outbound, b.len());
+        }
+
+        let format = *self.json_format.read().unwrap();
+        let mut frames = vec![packet.encode_with(format).into_bytes()];
+        frames.extend(attachments);
+        frames
+    }
+
+    fn record_packet(&self, direction: Direction, packet: &Packet) {
+        match direction {
+            Direction::Inbound => self.counters.packets_in.fetch_add(1, Relaxed),
+            Direction::Outbound => self.counters.packets_out.fetch_add(1, Relaxed),
+        };
+        if let Some(ref recorder) = *self.traffic.read().unwrap() {
+            recorder.packet(direction, &self.id(), packet);
+        }
+    }
+
+    fn record_attachment(&self, direction: Direction, len: usize) {
+        if let Some(ref recorder) = *self.traffic.read().unwrap() {
+            recorder.attachment(direction, &self.id(), len);
+        }
+    }
+
+    /// Add a hook called on every packet sent to the client, right
+    /// before it is encoded. Hooks run in the order they were added,
+    /// and can e.g. add metadata to or redact the packet's data. The
+    /// number of attachments is reset to the attachments actually
+    /// sent after all hooks ran.
+    pub fn use_outbound<F>(&self, f: F)
+        where F: Fn(&mut Packet) + 'static
+    {
+        self.outbound.write().unwrap().push(Box::new(f));
+    }
+
+    /// Reject the client's connection to the current namespace,
+    /// sending `data` (usually `{"message": ...}`) as the payload of
+    /// the connect error. v5 clients require an object, so any other
+    /// `data` is sent to them as `{"message": data}`.
+    pub fn send_connect_error(&self, data: Value) {
+        let data = connect_error_payload(self.protocol(), data);
+        self.send_packet(Packet::new_connect_error(self.namespace(), data));
+    }
+
+    /// Emit an event to the client, with the name `event`.
+    ///
+    /// This is fire and forget: the event is queued with engine.io
+    /// without asking for an ack, so no ack id is taken, and nothing
+    /// tells whether the client got it. Use `emit_confirmed` to find
+    /// out whether the connection was still open, or `emit_reliable`
+    /// for events that must reach the client.
+    ///
+    /// The event is handed to engine.io right away; how soon it's
+    /// written out is up to the transport. engine.io has no flush
+    /// call, so there's no option to trade throughput for latency by
+    /// writing each event out on its own.
+    pub fn emit(&self, event: Value, params: Option<Vec<Data>>) {
+        let (json, attachments) = encode_event(event, params);
+        self.emit_encoded(json, attachments);
+    }
+
+    /// Emit an event already encoded with `encode_event`, so
+    /// broadcasts only need to encode their parameters once.
+    #[doc(hidden)]
+    pub fn emit_encoded(&self, json: Value, attachments: Vec<Vec<u8>>) {
+        let packet = Packet::new_event(self.namespace(), None, attachments.len(), json);
+        self.send_packet_with_attachments(packet, attachments);
+    }
+
+    /// Emit several events to the client at once. All events are
+    /// encoded before any is sent, so they're queued with engine.io
+    /// back to back. engine.io has no batched write, but its polling
+    /// transport sends everything queued in a single response.
+    pub fn emit_batch(&self, events: Vec<(Value, Option<Vec<Data>>)>) {
+        let namespace = self.namespace();
+        let mut frames = vec![];
+        for (event, params) in events {
+            let (json, attachments) = encode_event(event, params);
+            let packet = Packet::new_event(namespace.clone(), None, attachments.len(), json);
+            frames.extend(self.encode_frames(packet, attachments));
+        }
+        for frame in frames {
+            self.send(frame);
+        }
+    }
+
+    /// Emit an event to the client, failing if the connection to the
+    /// client has been closed.
+    ///
+    /// engine.io doesn't report when a message has actually been
+    /// written to the transport, so this only guarantees the event
+    /// was queued for sending.
+    pub fn emit_confirmed(&self, event: Value, params: Option<Vec<Data>>) -> Result<(), SendError> {
+        let (json, attachments) = encode_event(event, params);
+        self.try_emit_encoded(json, attachments)
+    }
+
+    /// Emit an event to the client, asking it for an ack, and resend
+    /// it if the ack doesn't arrive within `timeout`, trying up to
+    /// `attempts` times in total. The client's handler has to ack the
+    /// event, and may see it more than once if an ack is lost.
+    ///
+    /// engine.io doesn't report when a message has been written to the
+    /// transport, so delivery is confirmed by the client's ack
+    /// instead. Fails with the error of the last attempt; rejected
+    /// events and closed connections aren't retried.
+    ///
+    /// This blocks the calling thread until the ack arrives, for up to
+    /// `attempts` times `timeout`. Like `request`, it must not be
+    /// called from an event handler, as acks are received on the
+    /// thread running it; use `emit_ack_retry` there instead.
+    pub fn emit_reliable(&self,
+                         event: Value,
+                         params: Option<Vec<Data>>,
+                         timeout: Duration,
+                         attempts: u32)
+                         -> Result<AckResponse, AckError> {
+        retry(attempts, || self.request(event.clone(), params.clone(), timeout))
+    }
+
+    /// Like `emit_reliable`, but waits for the ack on a new thread,
+    /// calling `on_result` with the ack, or the error of the last
+    /// attempt. Every attempt asks for an ack under a new id, and the
+    /// ack of a timed out attempt is forgotten, so an ack arriving
+    /// late doesn't count for a later attempt.
+    pub fn emit_ack_retry<F>(&self,
+                             event: Value,
+                             params: Option<Vec<Data>>,
+                             attempts: u32,
+                             per_attempt_timeout: Duration,
+                             on_result: F)
+        where F: FnOnce(Result<AckResponse, AckError>) + Send + 'static
+    {
+        let so = self.clone();
+        thread::spawn(move || {
+            on_result(so.emit_reliable(event, params, per_attempt_timeout, attempts))
+        });
+    }
+
+    /// Like `emit_encoded`, failing if the connection to the client has
+    /// been closed.
+    #[doc(hidden)]
+    pub fn try_emit_encoded(&self, json: Value, attachments: Vec<Vec<u8>>) -> Result<(), SendError> {
+        if self.is_closed() {
+            return Err(SendError::Closed);
+        }
+        self.emit_encoded(json, attachments);
+        Ok(())
+    }
+
+    /// Like `try_emit_encoded`, for an event shared by a broadcast, see
+    /// `send_shared`.
+    #[doc(hidden)]
+    pub fn try_emit_shared(&self, event: &SharedEvent) -> Result<(), SendError> {
+        if self.is_closed() {
+            return Err(SendError::Closed);
+        }
+        self.send_shared(None, event);
+        Ok(())
+    }
+
+    // Sends an event shared by a broadcast, reusing its serialized
+    // JSON. Outbound hooks and traffic recording need a packet of the
+    // socket's own, so the event's JSON is copied into one if either
+    // is set.
+    fn send_shared(&self, id: Option<u64>, event: &SharedEvent) {
+        let (namespace, attachments) = (self.namespace(), event.attachments());
+        if !self.outbound.read().unwrap().is_empty() || self.traffic.read().unwrap().is_some() {
+            let packet = Packet::new_event(namespace, id, attachments.len(), event.json().clone());
+            return self.send_packet_with_attachments(packet, attachments.to_vec());
+        }
+
+        self.counters.packets_out.fetch_add(1, Relaxed);
+        let header = Packet::new_event(namespace, id, attachments.len(), Value::Array(vec![]));
+        let json = event.serialized(*self.json_format.read().unwrap());
+        self.send(header.encode_with_json(&json).into_bytes());
+        for b in attachments {
+            self.send(b.clone());
+        }
+    }
+
+    /// Emit an event to the client, with `arg` serialized to JSON as
+    /// its only argument.
+    pub fn emit_one<T: Serialize>(&self, event: &str, arg: &T) -> Result<(), SendError> {
+        if self.is_closed() {
+            return Err(SendError::Closed);
+        }
+        let json = try!(encode_one(event, arg).map_err(SendError::Encode));
+        self.emit_encoded(json, vec![]);
+        Ok(())
+    }
+
+    /// Returns `true` if the connection to the client has been closed.
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Relaxed)
+    }
+
+    /// Emit an event to the client with a single binary attachment
+    /// `data` as its only parameter.
+    #[inline]
+    pub fn emit_binary(&self, event: &str, data: Vec<u8>) {
+        self.emit(Value::String(event.to_string()), Some(vec![Data::Binary(data)]));
+    }
+
+    /// Emit an event to the client, and ask the client for an
+    /// acknowledgment. Once received, call `on_ack`. Returns the id
+    /// of the ack, which can be passed to `cancel_ack`.
+    ///
+    /// `on_ack` is never called if the ack is cancelled, dropped or
+    /// the connection closes first.
+    pub fn emit_ack<F>(&self, event: Value, params: Option<Vec<Data>>, on_ack: F) -> u64
+        where F: Fn(Option<Value>, Option<Vec<Vec<u8>>>) + 'static
+    {
+        self.emit_with_ack(event, params, Box::new(move |result| {
+            if let Ok(response) = result {
+                on_ack(response.data, response.attachments);
+            }
+        }))
+    }
+
+    /// Limit the number of acks waited for at once to `max`. Once
+    /// exceeded, the oldest pending ack is dropped, failing with
+    /// `AckError::Dropped` if it was sent with `request`. `None`, the
+    /// default, doesn't limit pending acks.
+    pub fn set_max_pending_acks(&self, max: Option<usize>) {
+        *self.max_pending_acks.write().unwrap() = max;
+    }
+
+    /// Returns the ids of the events sent to the client that haven't
+    /// been acknowledged yet, sorted.
+    pub fn pending_acks(&self) -> Vec<u64> {
+        sorted_ids(&self.acks.lock().unwrap())
+    }
+
+    /// Stop waiting for the ack with the id `id`, dropping its
+    /// callback. Returns `false` if no such ack was pending.
+    pub fn cancel_ack(&self, id: u64) -> bool {
+        self.acks.lock().unwrap().remove(&id).is_some()
+    }
+
+    /// Like `emit_ack`, for an event shared by a broadcast, see
+    /// `send_shared`.
+    #[doc(hidden)]
+    pub fn emit_ack_shared<F>(&self, event: &SharedEvent, on_ack: F) -> u64
+        where F: Fn(Option<Value>, Option<Vec<Vec<u8>>>) + 'static
+    {
+        let ack_id = self.register_ack(Box::new(move |result| {
+            if let Ok(response) = result {
+                on_ack(response.data, response.attachments);
             }
+        }));
+        self.send_shared(Some(ack_id), event);
+        ack_id
+    }
+
+    fn emit_with_ack(&self,
+                     event: Value,
+                     params: Option<Vec<Data>>,
+                     on_ack: Box<Fn(Result<AckResponse, AckError>)>)
+                     -> u64 {
+        let (json, attachments) = encode_event(event, params);
+        self.emit_encoded_with_ack(json, attachments, on_ack)
+    }
+
+    fn emit_encoded_with_ack(&self,
+                             json: Value,
+                             attachments: Vec<Vec<u8>>,
+                             on_ack: Box<Fn(Result<AckResponse, AckError>)>)
+                             -> u64 {
+        let ack_id = self.register_ack(on_ack);
+        let packet = Packet::new_event(self.namespace(), Some(ack_id), attachments.len(), json);
+        self.send_packet_with_attachments(packet, attachments);
+        ack_id
+    }
+
+    // Stores `on_ack` under a new ack id, dropping the oldest acks past
+    // the limit set with `set_max_pending_acks`.
+    fn register_ack(&self, on_ack: Box<Fn(Result<AckResponse, AckError>)>) -> u64 {
+        let (ack_id, dropped) = {
+            let mut map = self.acks.lock().unwrap();
+            let ack_id = next_free_id(&mut self.last_ack_id.lock().unwrap(), &map);
+            map.insert(ack_id, (self.ack_seq.fetch_add(1, Relaxed), on_ack));
+            let dropped = match *self.max_pending_acks.read().unwrap() {
+                Some(max) => evict_oldest(&mut map, max),
+                None => vec![],
+            };
+            (ack_id, dropped)
+        };
+        for callback in dropped {
+            callback(Err(AckError::Dropped));
+        }
+        ack_id
+    }
+
+    /// Emit an event to the client and wait up to `timeout` for it to
+    /// be acknowledged. This is sugar over `emit_ack`.
+    ///
+    /// Acks are received on the thread handling the client's
+    /// messages, so this must not be called from an event handler;
+    /// use `request_async` there instead.
+    pub fn request(&self,
+                   event: Value,
+                   params: Option<Vec<Data>>,
+                   timeout: Duration)
+                   -> Result<AckResponse, AckError> {
+        if self.is_closed() {
+            return Err(AckError::Closed);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let ack_id = self.emit_with_ack(event, params, Box::new(move |result| {
+            let _ = tx.send(result);
+        }));
+
+        wait_for_ack(&rx, timeout, || {
+            self.acks.lock().unwrap().remove(&ack_id);
+        })
+    }
+
+    /// Emit an event to the client with `req` serialized to JSON as
+    /// its only argument, and wait up to `timeout` for the client to
+    /// acknowledge it. The first argument of the ack is deserialized
+    /// into the response. Like `request`, this must not be called from
+    /// an event handler.
+    pub fn call<Req, Resp>(&self,
+                           event: &str,
+                           req: &Req,
+                           timeout: Duration)
+                           -> Result<Resp, CallError>
+        where Req: Serialize,
+              Resp: Deserialize
+    {
+        let json = try!(to_json(req).map_err(CallError::Encode));
+        let response = try!(self.request(Value::String(event.to_string()),
+                                         Some(vec![Data::JSON(json)]),
+                                         timeout)
+            .map_err(CallError::Ack));
+        decode_response(response.data).map_err(CallError::Decode)
+    }
+
+    /// Like `request`, but waits for the ack on a new thread, calling
+    /// `f` with the result.
+    pub fn request_async<F>(&self,
+                            event: Value,
+                            params: Option<Vec<Data>>,
+                            timeout: Duration,
+                            f: F)
+        where F: FnOnce(Result<AckResponse, AckError>) + Send + 'static
+    {
+        let so = self.clone();
+        thread::spawn(move || f(so.request(event, params, timeout)));
+    }
+
+    /// Wait up to `timeout` for the client to send the event `event`,
+    /// returning its arguments and attachments. Handlers registered
+    /// for the event are still called as usual.
+    ///
+    /// Events are received on the thread handling the client's
+    /// messages, so this must not be called from an event handler.
+    pub fn next_event(&self,
+                      event: &str,
+                      timeout: Duration)
+                      -> Result<(Vec<Value>, Option<Vec<Vec<u8>>>), TimeoutError> {
+        if self.is_closed() {
+            return Err(TimeoutError);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let id = self.last_waiter_id.fetch_add(1, Relaxed);
+        self.waiters.lock().unwrap().entry(event.to_string()).or_insert(vec![]).push((id, tx));
+
+        rx.recv_timeout(timeout).map_err(|_| {
+            remove_waiter(&mut self.waiters.lock().unwrap(), event, id);
+            TimeoutError
+        })
+    }
+
+    /// Set the id of the next event sent to the client asking for an
+    /// ack. Ids count up from there, wrapping around after
+    /// `u64::MAX` and skipping ids still waiting for an ack. Defaults
+    /// to 0.
+    pub fn set_next_ack_id(&self, id: u64) {
+        *self.last_ack_id.lock().unwrap() = id;
+    }
+
+    /// Set a callback to be called once the connection to the client
+    /// is closed, for whatever reason.
+    pub fn on_close<F>(&self, f: F)
+        where F: Fn(&CloseReason) + 'static
+    {
+        *self.on_close.write().unwrap() = Some(Box::new(f));
+    }
+
+    /// Set a callback to be called when the connection's transport
+    /// fails, e.g. when the client stops answering pings. engine.io
+    /// only reports transport failures by closing the connection, so
+    /// this is called right before the `on_close` callback, which gets
+    /// `Initiator::Transport`. Malformed packets are reported to
+    /// `on_error` instead.
+    pub fn on_transport_error<F>(&self, f: F)
+        where F: Fn(&TransportError) + 'static
+    {
+        *self.on_transport_error.write().unwrap() = Some(Box::new(f));
+    }
+
+    /// Disconnect the client, sending it a `Disconnect` packet
+    /// before closing the connection.
+    pub fn disconnect(&self, reason: DisconnectReason) {
+        self.send_packet(Packet::new_disconnect(self.namespace()));
+        self.shutdown(Initiator::Server, reason.as_str());
+    }
+
+    /// Emit a final event, e.g. `"goodbye"` with a message for the
+    /// user, then disconnect the client like `disconnect`.
+    ///
+    /// engine.io has no flush, but it sends queued packets in order,
+    /// so the event is queued before the `Disconnect` packet, and
+    /// both before the connection is closed.
+    pub fn close_with_event(&self, event: Value, params: Option<Vec<Data>>,
+                            reason: DisconnectReason) {
+        for (packet, attachments) in farewell_packets(self.namespace(), event, params) {
+            self.send_packet_with_attachments(packet, attachments);
+        }
+        self.shutdown(Initiator::Server, reason.as_str());
+    }
+
+    /// Close the connection to the client.
+    #[inline(always)]
+    pub fn close(&mut self) {
+        self.shutdown(Initiator::Server, "close()");
+    }
+
+    fn shutdown(&self, initiator: Initiator, reason: &str) {
+        // engine.io may call its close callback right away, make sure
+        // the close is reported with the right initiator first.
+        self.closed_by(initiator, reason);
+        self.socket.close(reason);
+    }
+
+    fn closed_by(&self, initiator: Initiator, reason: &str) {
+        if self.closed.swap(true, Relaxed) {
+            return;
+        }
+
+        self.acks.lock().unwrap().clear();
+        self.waiters.lock().unwrap().clear();
+        self.server_clients.write().unwrap().retain(|so| so.id() != self.id());
+        {
+            let nsp = self.namespace().unwrap_or(DEFAULT_NAMESPACE.to_string());
+            if let Some(namespace) = self.namespaces.read().unwrap().get(&nsp) {
+                namespace.remove_socket(&self.id());
+            }
+        }
+        let rooms_joined = self.rooms_joined.read().unwrap().clone();
+        for room in rooms_joined.iter() {
+            self.adapter.remove(self, room);
+        }
+        self.adapter.remove(self, &self.id());
+        for room in rooms_joined {
+            self.fire_room_event(RoomEvent::Left {
+                socket_id: self.id(),
+                room: room,
+            });
+        }
+
+        if let Some(ref func) = *self.on_close.read().unwrap() {
+            func(&CloseReason {
+                initiator: initiator,
+                reason: reason.to_string(),
+            });
+        }
+    }
+}
+
+// Values attached to a socket with `Socket::set`, keyed by their type.
+#[derive(Default)]
+struct DataBag {
+    values: HashMap<TypeId, Arc<Any + Send + Sync>>,
+}
+
+impl DataBag {
+    fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.clone().downcast().ok())
+    }
+}
+
+// Backs `Socket::metrics`.
+#[derive(Default)]
+struct Counters {
+    packets_in: AtomicUsize,
+    packets_out: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+}
+
+// engine.io reports a client closing the connection as a "transport
+// close", anything else is the transport failing.
+fn engine_close_initiator(reason: &str) -> Initiator {
+    match reason {
+        "transport close" => Initiator::Client,
+        _ => Initiator::Transport,
+    }
+}
+
+// Runs the handler for the event `name`, catching a panic so it
+// doesn't take the connection's thread down with it, and later
+// events are still handled.
+fn call_handler<T, F: FnOnce() -> T>(name: &str, handler: F) -> Result<T, Error> {
+    panic::catch_unwind(AssertUnwindSafe(handler))
+        .map_err(|_| Error::HandlerPanicked(name.to_string()))
+}
+
+// Adds the frame `bytes` to the attachments of the buffered packet
+// `cur`, returning the packet once it has all of them.
+fn add_attachment(cur: &mut Option<Packet>, bytes: &[u8]) -> Option<Packet> {
+    let complete = match *cur {
+        Some(ref mut packet) => packet.add_attachment(bytes.to_vec()),
+        None => false,
+    };
+    if complete {
+        cur.take()
+    } else {
+        None
+    }
+}
+
+// Event names are usually strings, but clients may send any JSON
+// value as the name.
+fn event_name(event: &Value) -> String {
+    event.as_str().map_or(event.to_string(), |s| s.to_string())
+}
+
+// Splits the payload of an event packet into the event's name and
+// its arguments.
+fn split_event(packet: &Packet) -> Result<(String, &[Value]), Error> {
+    match packet.data {
+        Some(Value::Array(ref event)) if !event.is_empty() => {
+            Ok((event_name(&event[0]), &event[1..]))
+        }
+        _ => Err(Error::NoEvent),
+    }
+}
+
+fn unknown_event_reply(protocol: ProtocolVersion,
+                       shape: AckErrorShape,
+                       namespace: Option<String>,
+                       id: Option<u64>,
+                       error: Error)
+                       -> Option<Packet> {
+    match (id, protocol) {
+        (Some(id), _) => {
+            let error = AckError::Rejected(Value::String(error.to_string()));
+            Some(ack_error_packet(shape, namespace, id, error))
+        }
+        // v5 clients take every error packet to be a connect error.
+        (None, ProtocolVersion::V4) => Some(Packet::new_error(namespace, error)),
+        (None, ProtocolVersion::V5) => None,
+    }
+}
+
+fn ack_error_packet(shape: AckErrorShape,
+                    namespace: Option<String>,
+                    id: u64,
+                    error: AckError)
+                    -> Packet {
+    let payload = error.into_payload();
+    match shape {
+        AckErrorShape::Payload => {
+            let data = Value::Array(vec![ObjectBuilder::new().insert("error", payload).build()]);
+            Packet::new_ack(namespace, id, 0, data)
+        }
+        AckErrorShape::ErrorPacket => Packet::new_error_payload(namespace, payload),
+    }
+}
+
+// Returns the ack to send for the event with the id `id`, given what
+// its handler returned, or `None` if no handler is registered for it.
+fn event_ack(ack: Option<Result<Vec<Data>, AckError>>,
+             empty_acks: bool,
+             shape: AckErrorShape,
+             namespace: Option<String>,
+             id: u64)
+             -> Option<(Packet, Vec<Vec<u8>>)> {
+    match ack {
+        Some(Ok(ref ack)) if ack.is_empty() && !empty_acks => None,
+        Some(Ok(ack)) => {
+            let (json, binary) = encode_data(ack);
+            Some((Packet::new_ack(namespace, id, binary.len(), json), binary))
+        }
+        Some(Err(error)) => Some((ack_error_packet(shape, namespace, id, error), vec![])),
+        None if empty_acks => {
+            Some((Packet::new_ack(namespace, id, 0, Value::Array(vec![])), vec![]))
+        }
+        None => None,
+    }
+}
+
+// Calls `f` until it succeeds, for at most `attempts` times, as long
+// as it fails with an error worth retrying.
+fn retry<F>(attempts: u32, mut f: F) -> Result<AckResponse, AckError>
+    where F: FnMut() -> Result<AckResponse, AckError>
+{
+    let mut result = Err(AckError::Timeout);
+    for _ in 0..attempts {
+        result = f();
+        match result {
+            Err(AckError::Timeout) |
+            Err(AckError::Dropped) => continue,
+            _ => break,
+        }
+    }
+    result
+}
+
+// Adds `room` to the rooms a socket joined, returning `false` if it
+// was already in it.
+fn add_room(rooms: &mut Vec<String>, room: &str) -> bool {
+    if rooms.iter().any(|r| r == room) {
+        return false;
+    }
+    rooms.push(room.to_string());
+    true
+}
+
+// Removes `room` from the rooms a socket joined, returning `false` if
+// it wasn't in it.
+fn remove_room(rooms: &mut Vec<String>, room: &str) -> bool {
+    let len = rooms.len();
+    rooms.retain(|r| r != room);
+    rooms.len() != len
+}
+
+// The packets `close_with_event` sends, in order: the event, then
+// the `Disconnect` packet, each with its attachments.
+fn farewell_packets(namespace: Option<String>, event: Value, params: Option<Vec<Data>>)
+                    -> Vec<(Packet, Vec<Vec<u8>>)> {
+    let (json, attachments) = encode_event(event, params);
+    vec![(Packet::new_event(namespace.clone(), None, attachments.len(), json), attachments),
+         (Packet::new_disconnect(namespace), vec![])]
+}
+
+fn check_joined(rooms: &[String], room: &str) -> Result<(), NotInRoomError> {
+    if rooms.iter().any(|r| r == room) {
+        Ok(())
+    } else {
+        Err(NotInRoomError { room: room.to_string() })
+    }
+}
+
+fn sorted_ids<V>(acks: &HashMap<u64, V>) -> Vec<u64> {
+    let mut ids: Vec<u64> = acks.keys().cloned().collect();
+    ids.sort();
+    ids
+}
+
+// Returns the ack id `next`, or the first one after it not in
+// `pending`, advancing `next` past it.
+fn next_free_id<V>(next: &mut u64, pending: &HashMap<u64, V>) -> u64 {
+    loop {
+        let id = *next;
+        *next = next.wrapping_add(1);
+        if !pending.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
+// Removes the oldest entries of `acks` until at most `max` are left,
+// returning their callbacks. Entries are ordered by the sequence
+// number they're stored with, since ack ids wrap around and can be
+// set with `Socket::set_next_ack_id`.
+fn evict_oldest<V>(acks: &mut HashMap<u64, (usize, V)>, max: usize) -> Vec<V> {
+    let mut dropped = vec![];
+    while acks.len() > max {
+        let oldest = *acks.iter().min_by_key(|&(_, &(seq, _))| seq).unwrap().0;
+        dropped.push(acks.remove(&oldest).unwrap().1);
+    }
+    dropped
+}
+
+// Removes the waiter `id` for `event`, and the event's entry once
+// nothing waits for it anymore.
+fn remove_waiter<T>(waiters: &mut HashMap<String, Vec<(usize, T)>>, event: &str, id: usize) {
+    let empty = match waiters.get_mut(event) {
+        Some(waiting) => {
+            waiting.retain(|&(i, _)| i != id);
+            waiting.is_empty()
+        }
+        None => false,
+    };
+    if empty {
+        waiters.remove(event);
+    }
+}
+
+fn upload(args: Vec<Value>, attachments: Option<Vec<Vec<u8>>>) -> Result<Upload, AckError> {
+    let mut attachments = attachments.unwrap_or(vec![]);
+    if attachments.len() != 1 {
+        let message = format!("expected exactly one attachment, got {}", attachments.len());
+        return Err(AckError::Rejected(Value::String(message)));
+    }
+
+    Ok(Upload {
+        args: args.into_iter().filter(|arg| !is_placeholder(arg)).collect(),
+        data: attachments.pop().unwrap(),
+    })
+}
+
+fn encode_one<T: Serialize>(event: &str, arg: &T) -> Result<Value, JSONError> {
+    let json = try!(to_json(arg));
+    Ok(Value::Array(vec![Value::String(event.to_string()), json]))
+}
+
+#[doc(hidden)]
+pub fn to_json<T: Serialize>(value: &T) -> Result<Value, JSONError> {
+    let mut ser = Serializer::new();
+    try!(value.serialize(&mut ser));
+    Ok(ser.unwrap())
+}
+
+// v5 clients take every error packet to be a connect error, so
+// malformed packets aren't echoed to them.
+fn malformed_policy(policy: OnMalformed, protocol: ProtocolVersion) -> OnMalformed {
+    match (policy, protocol) {
+        (OnMalformed::Echo, ProtocolVersion::V5) => OnMalformed::Ignore,
+        (policy, _) => policy,
+    }
+}
+
+// Waits up to `timeout` for an ack callback to pass its result down
+// `rx`, calling `cancel` to forget the pending ack if it doesn't.
+fn wait_for_ack<F: FnOnce()>(rx: &mpsc::Receiver<Result<AckResponse, AckError>>,
+                             timeout: Duration,
+                             cancel: F)
+                             -> Result<AckResponse, AckError> {
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => {
+            cancel();
+            Err(AckError::Timeout)
+        }
+        // pending acks are dropped once the connection closes
+        Err(RecvTimeoutError::Disconnected) => Err(AckError::Closed),
+    }
+}
+
+// Deserializes the first argument of an ack, taking a missing one to
+// be `null`.
+fn decode_response<T: Deserialize>(data: Option<Value>) -> Result<T, JSONError> {
+    let first = match data {
+        Some(Value::Array(args)) => args.into_iter().next(),
+        _ => None,
+    };
+    from_value(first.unwrap_or(Value::Null))
+}
+
+fn connect_reply(protocol: ProtocolVersion, namespace: Option<String>, sid: &str) -> Option<Packet> {
+    match protocol {
+        ProtocolVersion::V4 => None,
+        ProtocolVersion::V5 => {
+            let data = ObjectBuilder::new().insert("sid", sid).build();
+            Some(Packet::new_connect(namespace, Some(data)))
+        }
+    }
+}
+
+fn receipt_packet(namespace: Option<String>, seq: u64) -> Packet {
+    let data = Value::Array(vec![Value::String(RECEIPT_EVENT.to_string()), Value::U64(seq)]);
+    Packet::new_event(namespace, None, 0, data)
+}
+
+// Clients may connect to any namespace if `allowed` is empty, and to
+// the default namespace, which they're in to begin with, regardless.
+fn namespace_allowed(allowed: &[String], namespace: Option<&str>) -> bool {
+    match namespace {
+        None | Some(DEFAULT_NAMESPACE) => true,
+        Some(nsp) => allowed.is_empty() || allowed.iter().any(|a| a == nsp),
+    }
+}
+
+fn connect_error_payload(protocol: ProtocolVersion, data: Value) -> Value {
+    match (protocol, data) {
+        (ProtocolVersion::V5, data @ Value::Object(_)) => data,
+        (ProtocolVersion::V5, data) => ObjectBuilder::new().insert("message", data).build(),
+        (ProtocolVersion::V4, data) => data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::{ack_error_packet, add_attachment, add_room, call_handler, check_joined,
+                connect_reply, connect_error_payload, decode_response, encode_one,
+                engine_close_initiator, event_ack, evict_oldest, farewell_packets, malformed_policy,
+                namespace_allowed, next_free_id, receipt_packet, remove_room, remove_waiter, retry,
+                sorted_ids, split_event, to_json, unknown_event_reply, upload, wait_for_ack,
+                AckError, AckErrorShape, AckResponse, DataBag, HandlerStat, Initiator,
+                NotInRoomError, OnMalformed};
+    use data::Data;
+    use packet::{Error, Packet, ProtocolVersion};
+    use serde::{Serialize, Serializer};
+    use serde_json::Value;
+    use serde_json::builder::ObjectBuilder;
+
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    impl Serialize for Point {
+        fn serialize<S: Serializer>(&self, s: &mut S) -> Result<(), S::Error> {
+            let mut state = try!(s.serialize_struct("Point", 2));
+            try!(s.serialize_struct_elt(&mut state, "x", self.x));
+            try!(s.serialize_struct_elt(&mut state, "y", self.y));
+            s.serialize_struct_end(state)
+        }
+    }
+
+    #[test]
+    fn event_acks() {
+        let encode = |ack, empty_acks| {
+            event_ack(ack, empty_acks, AckErrorShape::Payload, Some("/chat".to_string()), 4)
+                .map(|(packet, attachments)| (packet.encode(), attachments.len()))
+        };
+        assert_eq!(encode(None, true), Some(("3/chat,4[]".to_string(), 0)));
+        assert_eq!(encode(None, false), None);
+        assert_eq!(encode(Some(Ok(vec![])), true), Some(("3/chat,4[]".to_string(), 0)));
+        assert_eq!(encode(Some(Ok(vec![])), false), None);
+        assert_eq!(encode(Some(Ok(vec![Data::JSON(Value::U64(1)), Data::Binary(vec![1])])), false),
+                   Some(("61-/chat,4[1,{\"_placeholder\":true,\"num\":0}]".to_string(), 1)));
+        let error = AckError::Rejected(Value::String("denied".to_string()));
+        assert_eq!(encode(Some(Err(error)), false),
+                   Some(("3/chat,4[{\"error\":\"denied\"}]".to_string(), 0)));
+    }
+
+    #[test]
+    fn ack_error_payload() {
+        let error = AckError::Rejected(Value::String("denied".to_string()));
+        assert_eq!(ack_error_packet(AckErrorShape::Payload, None, 4, error).encode(),
+                   "34[{\"error\":\"denied\"}]");
+    }
+
+    #[test]
+    fn ack_error_packet_shape() {
+        let error = AckError::Rejected(Value::String("denied".to_string()));
+        assert_eq!(ack_error_packet(AckErrorShape::ErrorPacket, Some("/chat".to_string()), 4, error)
+                       .encode(),
+                   "4/chat,\"denied\"");
+    }
+
+    #[test]
+    fn v4_connect() {
+        let packet = Packet::from_bytes(b"0/chat").expect("Decoding packet");
+        assert_eq!(packet.data, None);
+        assert_eq!(connect_reply(ProtocolVersion::V4, packet.namespace, "abc"), None);
 
-            clients.swap_remove(i);
+        let error = Value::String("denied".to_string());
+        assert_eq!(connect_error_payload(ProtocolVersion::V4, error.clone()), error);
+    }
+
+    #[test]
+    fn v5_connect_with_auth() {
+        let packet = Packet::from_bytes(b"0/chat,{\"token\":\"123\"}").expect("Decoding packet");
+        assert_eq!(packet.data,
+                   Some(ObjectBuilder::new().insert("token", "123").build()));
+        assert_eq!(connect_reply(ProtocolVersion::V5, packet.namespace, "abc").unwrap().encode(),
+                   "0/chat,{\"sid\":\"abc\"}");
+
+        let error = Value::String("denied".to_string());
+        assert_eq!(connect_error_payload(ProtocolVersion::V5, error.clone()),
+                   ObjectBuilder::new().insert("message", error).build());
+    }
+
+    #[test]
+    fn receipts() {
+        assert_eq!(receipt_packet(None, 1).encode(), "2[\"$receipt\",1]");
+        assert_eq!(receipt_packet(Some("/chat".to_string()), 42).encode(),
+                   "2/chat,[\"$receipt\",42]");
+    }
+
+    #[test]
+    fn empty_event() {
+        let packet = Packet::new_event(None, Some(1), 0, Value::Array(vec![]));
+        assert_eq!(split_event(&packet).err().map(|e| e.to_string()),
+                   Some(Error::NoEvent.to_string()));
+
+        let packet = Packet::from_bytes(b"2[\"chat\",1]").expect("Decoding packet");
+        assert_eq!(split_event(&packet).ok(), Some(("chat".to_string(), &[Value::U64(1)][..])));
+    }
+
+    #[test]
+    fn handler_stat() {
+        let mut stat = HandlerStat::default();
+        stat.record(Duration::from_millis(5));
+        stat.record(Duration::from_millis(20));
+        stat.record(Duration::from_millis(10));
+        assert_eq!(stat,
+                   HandlerStat {
+                       count: 3,
+                       total: Duration::from_millis(35),
+                       max: Duration::from_millis(20),
+                   });
+    }
+
+    #[test]
+    fn allowed_namespaces() {
+        let allowed = vec!["/chat".to_string()];
+        assert!(namespace_allowed(&allowed, Some("/chat")));
+        assert!(!namespace_allowed(&allowed, Some("/admin")));
+        assert!(namespace_allowed(&allowed, None));
+        assert!(namespace_allowed(&allowed, Some("/")));
+        assert!(namespace_allowed(&[], Some("/admin")));
+    }
+
+    #[test]
+    fn engine_close_initiators() {
+        assert_eq!(engine_close_initiator("transport close"), Initiator::Client);
+        assert_eq!(engine_close_initiator("ping timeout"), Initiator::Transport);
+        assert_eq!(engine_close_initiator("transport error"), Initiator::Transport);
+    }
+
+    #[test]
+    fn emit_one() {
+        let json = encode_one("move", &Point { x: 1, y: -2 }).unwrap();
+        let encoded = Packet::new_event(None, None, 0, json).encode();
+        assert_eq!(encoded, "2[\"move\",{\"x\":1,\"y\":-2}]");
+
+        let decoded = Packet::from_bytes(encoded.as_bytes()).expect("Decoding packet");
+        let args = decoded.data.unwrap();
+        assert_eq!(args.as_array().unwrap()[1].find("y"), Some(&Value::I64(-2)));
+    }
+
+    #[test]
+    fn emit_one_encode_error() {
+        let mut map = BTreeMap::new();
+        map.insert(1, "one");
+        assert!(encode_one("numbers", &map).is_err());
+    }
+
+    #[test]
+    fn unknown_events() {
+        let error = || Error::UnknownEvent("typo".to_string());
+        let reply = |protocol, id| {
+            unknown_event_reply(protocol, AckErrorShape::Payload, None, id, error()).map(|p| p.encode())
+        };
+
+        assert_eq!(reply(ProtocolVersion::V4, Some(3)),
+                   Some("33[{\"error\":\"no handler for event \\\"typo\\\"\"}]".to_string()));
+        assert_eq!(reply(ProtocolVersion::V4, None),
+                   Some("4{\"code\":\"UNKNOWN_EVENT\",\"message\":\"no handler for event \\\"typo\\\"\"}"
+                       .to_string()));
+        assert_eq!(reply(ProtocolVersion::V5, None), None);
+    }
+
+    #[test]
+    fn call_round_trip() {
+        let request = to_json(&Point { x: 1, y: -2 }).unwrap();
+        let ack = Packet::from_bytes(format!("30[{}]", request).as_bytes()).expect("Decoding packet");
+
+        let response: BTreeMap<String, i32> = decode_response(ack.data).unwrap();
+        assert_eq!(response.get("x"), Some(&1));
+        assert_eq!(response.get("y"), Some(&-2));
+    }
+
+    #[test]
+    fn call_decode_errors() {
+        let ack = Packet::from_bytes(b"30[\"nope\"]").expect("Decoding packet");
+        assert!(decode_response::<BTreeMap<String, i32>>(ack.data).is_err());
+
+        let empty = Packet::from_bytes(b"30[]").expect("Decoding packet");
+        assert_eq!(decode_response::<Option<i32>>(empty.data).unwrap(), None);
+    }
+
+    #[test]
+    fn malformed_echo() {
+        assert_eq!(malformed_policy(OnMalformed::Echo, ProtocolVersion::V4), OnMalformed::Echo);
+        assert_eq!(malformed_policy(OnMalformed::Echo, ProtocolVersion::V5), OnMalformed::Ignore);
+    }
+
+    #[test]
+    fn malformed_disconnect() {
+        for &protocol in &[ProtocolVersion::V4, ProtocolVersion::V5] {
+            assert_eq!(malformed_policy(OnMalformed::Disconnect, protocol),
+                       OnMalformed::Disconnect);
+        }
+    }
+
+    #[test]
+    fn malformed_ignore() {
+        for &protocol in &[ProtocolVersion::V4, ProtocolVersion::V5] {
+            assert_eq!(malformed_policy(OnMalformed::Ignore, protocol), OnMalformed::Ignore);
+        }
+    }
+
+    #[test]
+    fn request_outcomes() {
+        let timeout = Duration::from_millis(20);
+        let response = AckResponse {
+            data: Some(Value::Array(vec![Value::U64(1)])),
+            attachments: None,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        tx.send(Ok(response.clone())).unwrap();
+        assert_eq!(wait_for_ack(&rx, timeout, || panic!("cancelled")), Ok(response));
+
+        tx.send(Err(AckError::Rejected(Value::Null))).unwrap();
+        assert_eq!(wait_for_ack(&rx, timeout, || panic!("cancelled")),
+                   Err(AckError::Rejected(Value::Null)));
+
+        let cancelled = Cell::new(false);
+        assert_eq!(wait_for_ack(&rx, timeout, || cancelled.set(true)), Err(AckError::Timeout));
+        assert!(cancelled.get());
+
+        // the callback holding `tx` is dropped when the socket closes
+        drop(tx);
+        assert_eq!(wait_for_ack(&rx, timeout, || panic!("cancelled")), Err(AckError::Closed));
+    }
+
+    #[test]
+    fn single_upload() {
+        let packet = Packet::from_bytes(b"51-1[\"avatar\",\"me.png\",{\"_placeholder\":true,\"num\":0}]")
+            .expect("Decoding packet");
+        let args: Vec<Value> = packet.data.unwrap().as_array().unwrap()[1..].to_vec();
+
+        let upload = upload(args, Some(vec![vec![1, 2, 3]])).unwrap();
+        assert_eq!(upload.args, vec![Value::String("me.png".to_string())]);
+        assert_eq!(upload.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn upload_attachment_count() {
+        assert!(upload(vec![], None).is_err());
+        assert_eq!(upload(vec![], Some(vec![vec![1], vec![2]])),
+                   Err(AckError::Rejected(Value::String("expected exactly one attachment, got 2"
+                       .to_string()))));
+    }
+
+    #[test]
+    fn evict_oldest_acks() {
+        let mut acks: HashMap<u64, (usize, &str)> =
+            vec![(3, (2, "c")), (1, (0, "a")), (2, (1, "b"))].into_iter().collect();
+        assert_eq!(evict_oldest(&mut acks, 3), Vec::<&str>::new());
+        assert_eq!(evict_oldest(&mut acks, 1), vec!["a", "b"]);
+        assert_eq!(acks.keys().collect::<Vec<_>>(), vec![&3]);
+
+        // after wrapping around, or `set_next_ack_id`, the oldest ack
+        // doesn't have the lowest id.
+        let mut acks: HashMap<u64, (usize, &str)> =
+            vec![(u64::MAX, (0, "a")), (0, (1, "b")), (7, (2, "c")), (1, (3, "d"))]
+                .into_iter()
+                .collect();
+        assert_eq!(evict_oldest(&mut acks, 2), vec!["a", "b"]);
+        let mut left: Vec<&u64> = acks.keys().collect();
+        left.sort();
+        assert_eq!(left, vec![&1, &7]);
+    }
+
+    #[test]
+    fn rooms_joined() {
+        let mut rooms = vec![];
+        assert!(add_room(&mut rooms, "a"));
+        assert!(add_room(&mut rooms, "b"));
+        assert!(add_room(&mut rooms, "c"));
+        assert!(!add_room(&mut rooms, "a"));
+        assert_eq!(rooms, vec!["a", "b", "c"]);
+
+        assert!(remove_room(&mut rooms, "b"));
+        assert!(!remove_room(&mut rooms, "b"));
+        assert!(!remove_room(&mut rooms, "x"));
+        assert_eq!(rooms, vec!["a", "c"]);
+
+        // rejoining a room moves it to the end
+        assert!(add_room(&mut rooms, "b"));
+        assert_eq!(rooms, vec!["a", "c", "b"]);
+    }
+
+    #[test]
+    fn farewell() {
+        let encode = |namespace: Option<&str>, params| {
+            farewell_packets(namespace.map(|n| n.to_string()),
+                             Value::String("goodbye".to_string()),
+                             params)
+                .into_iter()
+                .map(|(packet, attachments)| (packet.encode(), attachments))
+                .collect::<Vec<_>>()
+        };
+
+        let message = Data::JSON(Value::String("server restarting".to_string()));
+        assert_eq!(encode(None, Some(vec![message])),
+                   vec![("2[\"goodbye\",\"server restarting\"]".to_string(), vec![]),
+                        ("1".to_string(), vec![])]);
+        assert_eq!(encode(Some("/chat"), Some(vec![Data::Binary(vec![1, 2])])),
+                   vec![("51-/chat,[\"goodbye\",{\"_placeholder\":true,\"num\":0}]".to_string(),
+                         vec![vec![1, 2]]),
+                        ("1/chat".to_string(), vec![])]);
+    }
+
+    #[test]
+    fn broadcast_rooms_joined() {
+        let mut rooms = vec![];
+        let not_in = |room: &str| Err(NotInRoomError { room: room.to_string() });
+        assert_eq!(check_joined(&rooms, "game"), not_in("game"));
+
+        add_room(&mut rooms, "game");
+        assert_eq!(check_joined(&rooms, "game"), Ok(()));
+        assert_eq!(check_joined(&rooms, "lobby"), not_in("lobby"));
+        // the room named after the socket's id isn't joined with `join`
+        assert_eq!(check_joined(&rooms, "sid"), not_in("sid"));
+
+        remove_room(&mut rooms, "game");
+        assert_eq!(check_joined(&rooms, "game"), not_in("game"));
+    }
+
+    #[test]
+    fn pending_and_cancelled_acks() {
+        let mut acks: HashMap<u64, (usize, Box<Fn() -> &'static str>)> = HashMap::new();
+        assert_eq!(sorted_ids(&acks), Vec::<u64>::new());
+
+        let mut next = 0;
+        for (seq, name) in vec!["a", "b", "c"].into_iter().enumerate() {
+            let id = next_free_id(&mut next, &acks);
+            acks.insert(id, (seq, Box::new(move || name)));
+        }
+        assert_eq!(sorted_ids(&acks), vec![0, 1, 2]);
+
+        // what `cancel_ack` does
+        assert!(acks.remove(&1).is_some());
+        assert!(acks.remove(&1).is_none());
+        assert_eq!(sorted_ids(&acks), vec![0, 2]);
+        assert_eq!((acks[&2].1)(), "c");
+
+        // ids set with `set_next_ack_id` aren't handed out in order
+        acks.insert(9, (3, Box::new(|| "d")));
+        acks.insert(5, (4, Box::new(|| "e")));
+        assert_eq!(sorted_ids(&acks), vec![0, 2, 5, 9]);
+    }
+
+    #[test]
+    fn ack_ids_wrap_around() {
+        let mut pending = HashMap::new();
+        pending.insert(::std::u64::MAX, ());
+        pending.insert(0, ());
+
+        let mut next = ::std::u64::MAX - 1;
+        assert_eq!(next_free_id(&mut next, &pending), ::std::u64::MAX - 1);
+        assert_eq!(next_free_id(&mut next, &pending), 1);
+        assert_eq!(next_free_id(&mut next, &pending), 2);
+    }
+
+    #[test]
+    fn reliable_retries() {
+        let response = || AckResponse { data: None, attachments: None };
+
+        let mut calls = 0;
+        let result = retry(3, || {
+            calls += 1;
+            if calls < 3 { Err(AckError::Timeout) } else { Ok(response()) }
+        });
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+
+        calls = 0;
+        assert_eq!(retry(2, || { calls += 1; Err(AckError::Dropped) }).err(),
+                   Some(AckError::Dropped));
+        assert_eq!(calls, 2);
+
+        calls = 0;
+        assert_eq!(retry(5, || { calls += 1; Err(AckError::Closed) }).err(),
+                   Some(AckError::Closed));
+        assert_eq!(calls, 1);
+        assert_eq!(retry(0, || Ok(response())).err(), Some(AckError::Timeout));
+    }
+
+    #[derive(PartialEq, Debug)]
+    struct User {
+        id: u64,
+        role: &'static str,
+    }
+
+    #[derive(PartialEq, Debug)]
+    struct Room(&'static str);
+
+    #[test]
+    fn data_bag() {
+        let mut bag = DataBag::default();
+        assert!(bag.get::<User>().is_none());
+
+        bag.insert(User { id: 7, role: "admin" });
+        bag.insert(Room("lobby"));
+        assert_eq!(*bag.get::<User>().unwrap(), User { id: 7, role: "admin" });
+        assert_eq!(*bag.get::<Room>().unwrap(), Room("lobby"));
+        assert!(bag.get::<String>().is_none());
+
+        bag.insert(Room("game"));
+        assert_eq!(*bag.get::<Room>().unwrap(), Room("game"));
+        assert_eq!(bag.get::<User>().unwrap().id, 7);
+    }
+
+    #[test]
+    fn timed_out_waiters() {
+        let mut waiters = HashMap::new();
+        waiters.insert("ping".to_string(), vec![(0, "first"), (1, "second")]);
+
+        remove_waiter(&mut waiters, "ping", 0);
+        assert_eq!(waiters["ping"], vec![(1, "second")]);
+        remove_waiter(&mut waiters, "pong", 1);
+        remove_waiter(&mut waiters, "ping", 1);
+        assert!(waiters.is_empty());
+    }
+
+    #[test]
+    fn final_attachment() {
+        let mut cur = Some(Packet::from_bytes(b"52-[\"upload\",{\"_placeholder\":true,\"num\":0},\
+                                                {\"_placeholder\":true,\"num\":1}]")
+                               .unwrap());
+        assert_eq!(add_attachment(&mut cur, &[1, 2]), None);
+        assert!(cur.is_some());
+
+        // the last attachment is returned with its packet, even if it
+        // could be decoded as a packet of its own.
+        let packet = add_attachment(&mut cur, b"2[\"x\"]").expect("Completing packet");
+        assert_eq!(packet.get_attachments(), Some(vec![vec![1, 2], b"2[\"x\"]".to_vec()]));
+        assert_eq!(cur, None);
+        assert_eq!(add_attachment(&mut cur, &[3]), None);
+    }
+
+    #[test]
+    fn panicking_handler() {
+        let mut handled = vec![];
+        for event in &["boom", "chat", "boom", "chat"] {
+            let result = call_handler(event, || {
+                if *event == "boom" {
+                    panic!("handler failed");
+                }
+                event.len()
+            });
+            handled.push(result.map_err(|e| e.to_string()));
         }
+        let panicked = Error::HandlerPanicked("boom".to_string()).to_string();
+        assert_eq!(handled, vec![Err(panicked.clone()), Ok(4), Err(panicked), Ok(4)]);
     }
 }
@@ -0,0 +1,207 @@
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde_json::Value;
+use serde_json::builder::ObjectBuilder;
+use packet::Packet;
+
+/// Which way a recorded frame went.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        }
+    }
+}
+
+// How many lines may wait for the writer before records are dropped.
+const BUFFERED_RECORDS: usize = 4096;
+
+/// Records packets sent to and received from clients as JSON lines,
+/// see `Server::record_traffic`. Lines are written on a background
+/// thread, so recording doesn't wait on the writer. Records made
+/// while the writer is too far behind are dropped, and counted.
+pub struct TrafficRecorder {
+    tx: Mutex<SyncSender<String>>,
+    dropped: AtomicUsize,
+}
+
+impl TrafficRecorder {
+    pub fn new<W>(writer: W) -> TrafficRecorder
+        where W: Write + Send + 'static
+    {
+        TrafficRecorder::with_capacity(writer, BUFFERED_RECORDS)
+    }
+
+    fn with_capacity<W>(mut writer: W, capacity: usize) -> TrafficRecorder
+        where W: Write + Send + 'static
+    {
+        let (tx, rx) = mpsc::sync_channel::<String>(capacity);
+        // stops once the recorder is dropped, or the writer fails.
+        thread::spawn(move || {
+            for line in rx {
+                if writer.write_all(line.as_bytes()).and_then(|_| writer.flush()).is_err() {
+                    return;
+                }
+            }
+        });
+
+        TrafficRecorder {
+            tx: Mutex::new(tx),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of records dropped because the writer was
+    /// too far behind.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Relaxed)
+    }
+
+    /// Records `packet`, sent to or received from the client with the
+    /// id `socket_id`.
+    pub fn packet(&self, direction: Direction, socket_id: &str, packet: &Packet) {
+        self.write(packet_record(direction, socket_id, now_millis(), packet));
+    }
+
+    /// Records a binary attachment of `len` bytes.
+    pub fn attachment(&self, direction: Direction, socket_id: &str, len: usize) {
+        self.write(ObjectBuilder::new()
+            .insert("direction", direction.as_str())
+            .insert("socket", socket_id)
+            .insert("timestamp", now_millis())
+            .insert("attachment", len)
+            .build());
+    }
+
+    fn write(&self, record: Value) {
+        let sent = self.tx.lock().unwrap().try_send(format!("{}\n", record));
+        // once the writer failed, records go nowhere anyway.
+        if let Err(TrySendError::Full(_)) = sent {
+            self.dropped.fetch_add(1, Relaxed);
+        }
+    }
+}
+
+fn packet_record(direction: Direction, socket_id: &str, timestamp: u64, packet: &Packet) -> Value {
+    ObjectBuilder::new()
+        .insert("direction", direction.as_str())
+        .insert("socket", socket_id)
+        .insert("timestamp", timestamp)
+        .insert_object("packet", |builder| {
+            builder.insert("type", packet.opcode as u8 - b'0')
+                .insert("nsp", &packet.namespace)
+                .insert("id", packet.id)
+                .insert("attachments", packet.attachments_num)
+                .insert("data", &packet.data)
+        })
+        .build()
+}
+
+fn now_millis() -> u64 {
+    let now = UNIX_EPOCH.elapsed().unwrap_or(Duration::from_secs(0));
+    now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::Duration;
+
+    use super::{packet_record, Direction, TrafficRecorder};
+    use packet::Packet;
+
+    struct ChannelWriter(Sender<Vec<u8>>);
+
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.send(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Blocks in `write` until let through by `gate`, reporting when it
+    // does through `entered`.
+    struct GatedWriter {
+        entered: Sender<()>,
+        gate: Receiver<()>,
+    }
+
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.entered.send(());
+            let _ = self.gate.recv();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_packet() {
+        let packet = Packet::from_bytes(b"2/chat,4[\"hi\"]").expect("Decoding packet");
+        assert_eq!(packet_record(Direction::Inbound, "abc", 1000, &packet).to_string(),
+                   "{\"direction\":\"in\",\"packet\":{\"attachments\":0,\"data\":[\"hi\"],\
+                    \"id\":4,\"nsp\":\"/chat\",\"type\":2},\"socket\":\"abc\",\"timestamp\":1000}");
+    }
+
+    #[test]
+    fn record_exchange() {
+        let (tx, rx) = mpsc::channel();
+        let recorder = TrafficRecorder::new(ChannelWriter(tx));
+        recorder.packet(Direction::Inbound, "abc", &Packet::from_bytes(b"21[\"ping\"]").unwrap());
+        recorder.packet(Direction::Outbound, "abc", &Packet::from_bytes(b"31[]").unwrap());
+
+        let timeout = Duration::from_secs(5);
+        let lines: Vec<String> = (0..2)
+            .map(|_| String::from_utf8(rx.recv_timeout(timeout).unwrap()).unwrap())
+            .collect();
+        assert!(lines[0].starts_with("{\"direction\":\"in\",\"packet\":{\"attachments\":0,\
+                                      \"data\":[\"ping\"],\"id\":1,\"nsp\":null,\"type\":2}"));
+        assert!(lines[1].starts_with("{\"direction\":\"out\""));
+        assert!(lines.iter().all(|line| line.ends_with("}\n")));
+    }
+
+    #[test]
+    fn drop_when_full() {
+        let (entered_tx, entered) = mpsc::channel();
+        let (open, gate) = mpsc::channel();
+        let recorder = TrafficRecorder::with_capacity(GatedWriter {
+                                                          entered: entered_tx,
+                                                          gate: gate,
+                                                      },
+                                                      1);
+        let packet = Packet::from_bytes(b"21[\"ping\"]").unwrap();
+
+        // the writer holds the first record, the channel the second.
+        recorder.packet(Direction::Inbound, "abc", &packet);
+        entered.recv_timeout(Duration::from_secs(5)).unwrap();
+        for _ in 0..3 {
+            recorder.packet(Direction::Inbound, "abc", &packet);
+        }
+        assert_eq!(recorder.dropped(), 2);
+
+        open.send(()).unwrap();
+        entered.recv_timeout(Duration::from_secs(5)).unwrap();
+        open.send(()).unwrap();
+    }
+}